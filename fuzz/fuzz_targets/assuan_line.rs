@@ -0,0 +1,64 @@
+//! Parses arbitrary lines in both directions. Parsing must never panic, and
+//! anything that parses has to come back unchanged from encoding it again.
+
+#![no_main]
+
+#[path = "../../src/assuan.rs"]
+#[allow(dead_code)]
+mod assuan;
+
+use assuan::{Request, Response};
+use libfuzzer_sys::fuzz_target;
+
+/// Parses every line of `encoded` with `parse`.
+fn reparse<T>(encoded: &[u8], parse: impl Fn(&[u8]) -> Result<T, assuan::LineError>) -> Vec<T> {
+    let encoded = encoded.strip_suffix(b"\n").expect("encoded lines end in LF");
+    encoded
+        .split(|&b| b == b'\n')
+        .map(|line| {
+            assert!(line.len() < assuan::MAX_LINE_LEN, "encoded line too long");
+            parse(line).expect("encoded line parses")
+        })
+        .collect()
+}
+
+fuzz_target!(|line: &[u8]| {
+    // Lines are split on LF before they are parsed.
+    if line.contains(&b'\n') {
+        return;
+    }
+
+    if let Ok(request) = Request::parse(line) {
+        let reparsed = reparse(&request.encode(), Request::parse);
+        match &request {
+            Request::Data(data) => {
+                let mut joined = Vec::new();
+                for request in reparsed {
+                    let Request::Data(chunk) = request else {
+                        panic!("data encoded as {request:?}");
+                    };
+                    joined.extend(chunk);
+                }
+                assert_eq!(&joined, data);
+            }
+            _ => assert_eq!(reparsed, [request]),
+        }
+    }
+
+    if let Ok(response) = Response::parse(line) {
+        let reparsed = reparse(&response.encode(), Response::parse);
+        match &response {
+            Response::Data(data) => {
+                let mut joined = Vec::new();
+                for response in reparsed {
+                    let Response::Data(chunk) = response else {
+                        panic!("data encoded as {response:?}");
+                    };
+                    joined.extend(chunk);
+                }
+                assert_eq!(&joined, data);
+            }
+            _ => assert_eq!(reparsed, [response]),
+        }
+    }
+});
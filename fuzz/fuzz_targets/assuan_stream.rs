@@ -0,0 +1,29 @@
+//! Splits an arbitrary byte stream into Assuan lines through a small read
+//! buffer, checking that the line limit holds however the input is chunked.
+
+#![no_main]
+
+#[path = "../../src/assuan.rs"]
+#[allow(dead_code)]
+mod assuan;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("runtime");
+
+    runtime.block_on(async {
+        let mut reader = BufReader::with_capacity(7, data);
+        let mut consumed = 0;
+
+        while let Ok(Some(line)) = assuan::read_line(&mut reader).await {
+            assert!(line.len() < assuan::MAX_LINE_LEN);
+            assert!(!line.contains(&b'\n'));
+            assert_eq!(&data[consumed..consumed + line.len()], &line[..]);
+            consumed += line.len() + 1;
+        }
+    });
+});
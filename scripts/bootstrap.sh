@@ -2,20 +2,25 @@
 set -euo pipefail
 
 # Bootstrap wsl2-bridge-rs from inside WSL2.
-# Downloads the latest release binary to a Windows-accessible path and
+# Downloads the latest release binaries (the Windows relay to a
+# Windows-accessible path, the Linux listener to a local bin directory) and
 # installs the systemd user services.
 #
 # Usage:
-#   bootstrap.sh [--bin-dir /mnt/c/tools] [--scope user|system]
+#   bootstrap.sh [--bin-dir /mnt/c/tools] [--linux-bin-dir /usr/local/bin] [--scope user|system]
 #
 # Options:
-#   --bin-dir   Directory (WSL path) where wsl2-bridge-rs.exe is placed.
-#               Defaults to /mnt/c/tools.
-#   --scope     Systemd install scope: user (default) or system.
+#   --bin-dir        Directory (WSL path) where wsl2-bridge-rs.exe is placed.
+#                    Defaults to /mnt/c/tools.
+#   --linux-bin-dir  Directory where the Linux wsl2-bridge-rs binary is placed.
+#                    Defaults to /usr/local/bin.
+#   --scope          Systemd install scope: user (default) or system.
 
 REPO="ArturoGuerra/wsl2-bridge-rs"
 BIN_NAME="wsl2-bridge-rs.exe"
+LINUX_BIN_NAME="wsl2-bridge-rs"
 BIN_DIR="/mnt/c/tools"
+LINUX_BIN_DIR="/usr/local/bin"
 SCOPE="user"
 RAW_BASE="https://raw.githubusercontent.com/${REPO}/main/scripts"
 
@@ -47,20 +52,25 @@ while [[ $# -gt 0 ]]; do
       BIN_DIR=$2; shift 2 ;;
     --bin-dir=*)
       BIN_DIR=${1#*=}; shift ;;
+    --linux-bin-dir)
+      [[ $# -ge 2 ]] || err "Missing value for --linux-bin-dir"
+      LINUX_BIN_DIR=$2; shift 2 ;;
+    --linux-bin-dir=*)
+      LINUX_BIN_DIR=${1#*=}; shift ;;
     --scope)
       [[ $# -ge 2 ]] || err "Missing value for --scope"
       SCOPE=$2; shift 2 ;;
     --scope=*)
       SCOPE=${1#*=}; shift ;;
     -h|--help)
-      sed -n '3,14p' "$0" | sed 's/^# \?//' 2>/dev/null || echo "See script source for usage."
+      sed -n '3,18p' "$0" | sed 's/^# \?//' 2>/dev/null || echo "See script source for usage."
       exit 0 ;;
     *)
       err "Unknown argument: $1" ;;
   esac
 done
 
-for cmd in curl socat systemctl; do
+for cmd in curl systemctl; do
   command -v "$cmd" >/dev/null 2>&1 || err "'$cmd' is required but not found"
 done
 
@@ -71,29 +81,40 @@ step "Fetching latest release from github.com/$REPO"
 
 release_json=$(curl -fsSL "https://api.github.com/repos/${REPO}/releases/latest")
 tag=$(echo "$release_json" | grep '"tag_name"' | head -1 | sed 's/.*"tag_name": *"\([^"]*\)".*/\1/')
-download_url=$(echo "$release_json" | grep '"browser_download_url"' | grep "${BIN_NAME}" | head -1 | sed 's/.*"browser_download_url": *"\([^"]*\)".*/\1/')
+asset_url() {
+  echo "$release_json" | grep '"browser_download_url"' | grep "/$1\"" | head -1 | sed 's/.*"browser_download_url": *"\([^"]*\)".*/\1/'
+}
+download_url=$(asset_url "${BIN_NAME}")
+linux_download_url=$(asset_url "${LINUX_BIN_NAME}")
 
-[[ -n $tag ]]          || err "Could not parse tag from GitHub API response"
-[[ -n $download_url ]] || err "No asset named '${BIN_NAME}' found in release ${tag}"
+[[ -n $tag ]]                || err "Could not parse tag from GitHub API response"
+[[ -n $download_url ]]       || err "No asset named '${BIN_NAME}' found in release ${tag}"
+[[ -n $linux_download_url ]] || err "No asset named '${LINUX_BIN_NAME}' found in release ${tag}"
 
 echo "    Latest release: $tag"
 
 # ---------------------------------------------------------------------------
-# 2. Download binary
+# 2. Download binaries
 # ---------------------------------------------------------------------------
-step "Downloading $BIN_NAME to $BIN_DIR"
-
 tmp_bin=$(mktemp /tmp/wsl2-bridge-rs.XXXXXX)
 trap 'rm -f "$tmp_bin"; rm -rf "${WORK_DIR:-}"' EXIT
-curl -fsSL "$download_url" -o "$tmp_bin"
 
-if ! mkdir -p "$BIN_DIR" 2>/dev/null || ! install -m755 "$tmp_bin" "${BIN_DIR}/${BIN_NAME}" 2>/dev/null; then
-  echo "    Requires elevated privileges, prompting for sudo..."
-  sudo mkdir -p "$BIN_DIR"
-  sudo install -m755 -o "$(id -u)" -g "$(id -g)" "$tmp_bin" "${BIN_DIR}/${BIN_NAME}"
-fi
+# download <url> <dir> <name>
+download() {
+  step "Downloading $3 to $2"
+  curl -fsSL "$1" -o "$tmp_bin"
+
+  if ! mkdir -p "$2" 2>/dev/null || ! install -m755 "$tmp_bin" "$2/$3" 2>/dev/null; then
+    echo "    Requires elevated privileges, prompting for sudo..."
+    sudo mkdir -p "$2"
+    sudo install -m755 -o "$(id -u)" -g "$(id -g)" "$tmp_bin" "$2/$3"
+  fi
+
+  echo "    Saved to $2/$3"
+}
 
-echo "    Saved to ${BIN_DIR}/${BIN_NAME}"
+download "$download_url" "$BIN_DIR" "$BIN_NAME"
+download "$linux_download_url" "$LINUX_BIN_DIR" "$LINUX_BIN_NAME"
 
 # ---------------------------------------------------------------------------
 # 3. Install systemd services
@@ -102,14 +123,16 @@ step "Installing systemd services (scope: $SCOPE)"
 
 bash "$MANAGE_SCRIPT" install \
   --scope "$SCOPE" \
-  --bin-path "${BIN_DIR}/${BIN_NAME}"
+  --bin-path "${BIN_DIR}/${BIN_NAME}" \
+  --linux-bin-path "${LINUX_BIN_DIR}/${LINUX_BIN_NAME}"
 
 # ---------------------------------------------------------------------------
 # Done
 # ---------------------------------------------------------------------------
 echo ""
 echo "Bootstrap complete."
-echo "  Binary : ${BIN_DIR}/${BIN_NAME}"
+echo "  Windows relay  : ${BIN_DIR}/${BIN_NAME}"
+echo "  Linux listener : ${LINUX_BIN_DIR}/${LINUX_BIN_NAME}"
 echo "  Services installed via systemd ($SCOPE scope)"
 echo ""
 echo "If SSH_AUTH_SOCK is not set in your shell, add to ~/.bashrc or ~/.zshrc:"
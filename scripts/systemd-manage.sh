@@ -4,6 +4,7 @@ set -euo pipefail
 usage() {
   cat <<'EOF'
 Usage: systemd-manage.sh <install|uninstall> [--scope user|system] [--bin-path /path/to/wsl2-bridge-rs.exe]
+                          [--linux-bin-path /path/to/wsl2-bridge-rs]
 
 Install or uninstall the provided systemd user services either for the current
 user (default) or globally in /etc/systemd/user.
@@ -11,6 +12,9 @@ user (default) or globally in /etc/systemd/user.
 The --bin-path option sets the path to the wsl2-bridge-rs.exe binary that will
 be written into the installed service files. Defaults to /mnt/c/tools/wsl2-bridge-rs.exe.
 
+The --linux-bin-path option sets the path to the Linux wsl2-bridge-rs binary that
+listens on the Unix sockets. Defaults to /usr/local/bin/wsl2-bridge-rs.
+
 Examples:
   systemd-manage.sh install
   systemd-manage.sh install --scope system
@@ -44,6 +48,7 @@ fi
 ACTION=""
 SCOPE="user"
 BIN_PATH="/mnt/c/tools/wsl2-bridge-rs.exe"
+LINUX_BIN_PATH="/usr/local/bin/wsl2-bridge-rs"
 
 while [[ $# -gt 0 ]]; do
   case $1 in
@@ -69,6 +74,15 @@ while [[ $# -gt 0 ]]; do
       BIN_PATH=${1#*=}
       shift
       ;;
+    --linux-bin-path)
+      [[ $# -ge 2 ]] || err "Missing value for --linux-bin-path"
+      LINUX_BIN_PATH=$2
+      shift 2
+      ;;
+    --linux-bin-path=*)
+      LINUX_BIN_PATH=${1#*=}
+      shift
+      ;;
     -h|--help)
       usage
       exit 0
@@ -134,7 +148,8 @@ install_units() {
   for unit_path in "${UNITS[@]}"; do
     local unit_name
     unit_name=$(basename "${unit_path}")
-    sed "s|@WSL2_BRIDGE_BIN@|${BIN_PATH}|g" "${unit_path}" \
+    sed -e "s|@WSL2_BRIDGE_BIN@|${BIN_PATH}|g" \
+      -e "s|@WSL2_BRIDGE_LINUX_BIN@|${LINUX_BIN_PATH}|g" "${unit_path}" \
       | install -Dm644 /dev/stdin "${dest}/${unit_name}"
   done
 
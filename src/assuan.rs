@@ -0,0 +1,745 @@
+//! The Assuan protocol spoken by gpg-agent.
+//!
+//! Assuan is line based: every line ends in LF and is at most
+//! [`MAX_LINE_LEN`] bytes long, LF included. The client sends a command and
+//! the server answers with any number of `S`, `#` and `D` lines followed by
+//! `OK` or `ERR`. While processing a command the server may `INQUIRE` for
+//! more data, which the client sends as `D` lines finished by `END`, or
+//! refuses with `CAN`.
+//!
+//! This module does not depend on the rest of the crate so the fuzz targets
+//! can build it on its own.
+
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Longest line accepted, including the terminating LF.
+pub const MAX_LINE_LEN: usize = 1000;
+
+/// Error source used in `ERR` lines produced by the relay itself, so that
+/// gpg reports them as coming from `<GPG Agent>`.
+const GPG_ERR_SOURCE_GPGAGENT: u32 = 4;
+
+/// `GPG_ERR_FORBIDDEN`
+pub const GPG_ERR_FORBIDDEN: u32 = 251;
+/// `GPG_ERR_ASS_SYNTAX`
+pub const GPG_ERR_ASS_SYNTAX: u32 = 276;
+
+/// Commands forwarded by a restricted relay: what a remote gpg needs to sign
+/// and decrypt with keys already in the agent, mirroring what gpg-agent
+/// permits on its extra socket.
+pub const RESTRICTED_COMMANDS: &[&str] = &[
+    "BYE",
+    "GETEVENTCOUNTER",
+    "GETINFO",
+    "HAVEKEY",
+    "HELP",
+    "ISTRUSTED",
+    "KEYINFO",
+    "LISTTRUSTED",
+    "NOP",
+    "OPTION",
+    "PKDECRYPT",
+    "PKSIGN",
+    "READKEY",
+    "RESET",
+    "SETHASH",
+    "SETKEY",
+    "SETKEYDESC",
+    "SIGKEY",
+];
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum LineError {
+    #[error("Assuan line exceeds {MAX_LINE_LEN} bytes")]
+    TooLong,
+
+    #[error("Empty Assuan line")]
+    Empty,
+
+    #[error("Invalid Assuan command name {0:?}")]
+    InvalidCommand(String),
+
+    #[error("Unknown Assuan response {0:?}")]
+    UnknownResponse(String),
+
+    #[error("Invalid error code in Assuan ERR line")]
+    InvalidErrorCode,
+
+    #[error("Missing keyword in Assuan {0} line")]
+    MissingKeyword(&'static str),
+
+    #[error("Invalid percent escape in Assuan line")]
+    InvalidEscape,
+}
+
+impl From<LineError> for io::Error {
+    fn from(err: LineError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A line sent by the client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// `D`, with the percent escapes decoded.
+    Data(Vec<u8>),
+    /// `END`, finishing the data sent for an inquiry.
+    End,
+    /// `CAN`, refusing an inquiry.
+    Cancel,
+    /// `#`, ignored by the server.
+    Comment(Vec<u8>),
+    /// Any other command, e.g. `GETINFO version`. Arguments are left as sent
+    /// because their escaping depends on the command.
+    Command { name: String, args: Vec<u8> },
+}
+
+/// A line sent by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    Ok(Vec<u8>),
+    Err {
+        code: u32,
+        description: Vec<u8>,
+    },
+    /// `S`, a status line.
+    Status {
+        keyword: String,
+        args: Vec<u8>,
+    },
+    Comment(Vec<u8>),
+    /// `D`, with the percent escapes decoded.
+    Data(Vec<u8>),
+    Inquire {
+        keyword: String,
+        args: Vec<u8>,
+    },
+    End,
+}
+
+impl Request {
+    /// The command name in upper case, as Assuan matches it.
+    pub fn command(&self) -> Option<String> {
+        match self {
+            Request::Command { name, .. } => Some(name.to_ascii_uppercase()),
+            _ => None,
+        }
+    }
+
+    /// Parses one line, without its LF.
+    pub fn parse(line: &[u8]) -> Result<Request, LineError> {
+        check_length(line)?;
+        let (word, rest) = split_word(line);
+
+        Ok(match word {
+            b"" if rest.is_empty() => return Err(LineError::Empty),
+            b"D" => Request::Data(unescape(rest)?),
+            b"END" => Request::End,
+            b"CAN" => Request::Cancel,
+            _ if word.starts_with(b"#") => Request::Comment(line[1..].to_vec()),
+            _ => Request::Command {
+                name: command_name(word)?,
+                args: trim_start(rest).to_vec(),
+            },
+        })
+    }
+
+    /// Encodes the request as one or more lines, each ending in LF.
+    // Only the fuzz targets re-encode requests; the relay forwards lines as sent.
+    #[allow(dead_code)]
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Request::Data(data) => encode_data(data),
+            Request::End => b"END\n".to_vec(),
+            Request::Cancel => b"CAN\n".to_vec(),
+            Request::Comment(text) => line(b"#", text),
+            Request::Command { name, args } => with_args(name.as_bytes(), args),
+        }
+    }
+}
+
+impl Response {
+    /// Parses one line, without its LF.
+    pub fn parse(line: &[u8]) -> Result<Response, LineError> {
+        check_length(line)?;
+        let (word, rest) = split_word(line);
+
+        Ok(match word {
+            b"" if rest.is_empty() => return Err(LineError::Empty),
+            b"OK" => Response::Ok(rest.to_vec()),
+            b"ERR" => {
+                let (code, description) = split_word(rest);
+                let code = std::str::from_utf8(code)
+                    .ok()
+                    .and_then(|code| code.parse().ok())
+                    .ok_or(LineError::InvalidErrorCode)?;
+                Response::Err {
+                    code,
+                    description: description.to_vec(),
+                }
+            }
+            b"S" => {
+                let (keyword, args) = keyword("S", rest)?;
+                Response::Status { keyword, args }
+            }
+            b"D" => Response::Data(unescape(rest)?),
+            b"INQUIRE" => {
+                let (keyword, args) = keyword("INQUIRE", rest)?;
+                Response::Inquire { keyword, args }
+            }
+            b"END" => Response::End,
+            _ if word.starts_with(b"#") => Response::Comment(line[1..].to_vec()),
+            _ => {
+                return Err(LineError::UnknownResponse(
+                    String::from_utf8_lossy(word).into_owned(),
+                ));
+            }
+        })
+    }
+
+    /// An `ERR` line as gpg-agent would send it for `code`.
+    pub fn error(code: u32, description: &str) -> Response {
+        Response::Err {
+            code: (GPG_ERR_SOURCE_GPGAGENT << 24) | code,
+            description: format!("{description} <GPG Agent>").into_bytes(),
+        }
+    }
+
+    /// Whether this line finishes the answer to a command.
+    pub fn is_final(&self) -> bool {
+        matches!(self, Response::Ok(_) | Response::Err { .. })
+    }
+
+    /// Encodes the response as one or more lines, each ending in LF.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Response::Ok(text) => with_args(b"OK", text),
+            Response::Err { code, description } => {
+                with_args(format!("ERR {code}").as_bytes(), description)
+            }
+            Response::Status { keyword, args } => {
+                with_args(format!("S {keyword}").as_bytes(), args)
+            }
+            Response::Comment(text) => line(b"#", text),
+            Response::Data(data) => encode_data(data),
+            Response::Inquire { keyword, args } => {
+                with_args(format!("INQUIRE {keyword}").as_bytes(), args)
+            }
+            Response::End => b"END\n".to_vec(),
+        }
+    }
+}
+
+fn check_length(line: &[u8]) -> Result<(), LineError> {
+    if line.len() + 1 > MAX_LINE_LEN {
+        return Err(LineError::TooLong);
+    }
+    Ok(())
+}
+
+/// Splits off the first space-separated word, dropping the space after it.
+fn split_word(line: &[u8]) -> (&[u8], &[u8]) {
+    match line.iter().position(|&b| b == b' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, &[]),
+    }
+}
+
+fn trim_start(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != b' ').unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn command_name(word: &[u8]) -> Result<String, LineError> {
+    let valid = word
+        .iter()
+        .all(|&b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if word.is_empty() || !valid {
+        return Err(LineError::InvalidCommand(
+            String::from_utf8_lossy(word).into_owned(),
+        ));
+    }
+    Ok(String::from_utf8(word.to_vec()).expect("ASCII"))
+}
+
+fn keyword(kind: &'static str, rest: &[u8]) -> Result<(String, Vec<u8>), LineError> {
+    let (keyword, args) = split_word(trim_start(rest));
+    if keyword.is_empty() {
+        return Err(LineError::MissingKeyword(kind));
+    }
+    let keyword =
+        String::from_utf8(keyword.to_vec()).map_err(|_| LineError::MissingKeyword(kind))?;
+    Ok((keyword, args.to_vec()))
+}
+
+fn line(prefix: &[u8], text: &[u8]) -> Vec<u8> {
+    [prefix, text, b"\n"].concat()
+}
+
+fn with_args(prefix: &[u8], args: &[u8]) -> Vec<u8> {
+    if args.is_empty() {
+        line(prefix, args)
+    } else {
+        [prefix, b" ", args, b"\n"].concat()
+    }
+}
+
+/// Decodes `%XX` escapes.
+pub fn unescape(bytes: &[u8]) -> Result<Vec<u8>, LineError> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+
+    while let Some((&b, tail)) = rest.split_first() {
+        if b != b'%' {
+            out.push(b);
+            rest = tail;
+            continue;
+        }
+
+        let hex = tail.get(..2).ok_or(LineError::InvalidEscape)?;
+        let hex = std::str::from_utf8(hex).map_err(|_| LineError::InvalidEscape)?;
+        out.push(u8::from_str_radix(hex, 16).map_err(|_| LineError::InvalidEscape)?);
+        rest = &tail[2..];
+    }
+
+    Ok(out)
+}
+
+/// Escapes the bytes that cannot appear literally in a `D` line.
+pub fn escape(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'%' | b'\r' | b'\n' => out.extend_from_slice(format!("%{b:02X}").as_bytes()),
+            b => out.push(b),
+        }
+    }
+    out
+}
+
+/// Encodes `data` as `D` lines, splitting it so no line is too long.
+fn encode_data(data: &[u8]) -> Vec<u8> {
+    // "D " and the LF take 3 bytes; an escape takes 3 bytes per input byte.
+    const ROOM: usize = MAX_LINE_LEN - 3;
+
+    let mut out = Vec::new();
+    let mut current = b"D ".to_vec();
+    for &b in data {
+        let escaped = escape(&[b]);
+        if current.len() - 2 + escaped.len() > ROOM {
+            current.push(b'\n');
+            out.append(&mut current);
+            current.extend_from_slice(b"D ");
+        }
+        current.extend_from_slice(&escaped);
+    }
+    current.push(b'\n');
+    out.append(&mut current);
+    out
+}
+
+/// Reads one line without its LF, or `None` on a clean EOF.
+///
+/// Lines longer than [`MAX_LINE_LEN`] are an error rather than being read
+/// into memory in full.
+pub async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let (chunk, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (&available[..i], Some(i + 1)),
+            None => (available, None),
+        };
+        if line.len() + chunk.len() + 1 > MAX_LINE_LEN {
+            return Err(LineError::TooLong.into());
+        }
+        line.extend_from_slice(chunk);
+
+        match done {
+            Some(consumed) => {
+                reader.consume(consumed);
+                return Ok(Some(line));
+            }
+            None => {
+                let consumed = chunk.len();
+                reader.consume(consumed);
+            }
+        }
+    }
+}
+
+/// Which commands a relay forwards. Data, `END`, `CAN` and comments are
+/// always forwarded since inquiries cannot be answered without them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandPolicy {
+    /// Upper-case command names, or `None` to forward every command.
+    allowed: Option<Vec<String>>,
+}
+
+impl CommandPolicy {
+    /// Forwards [`RESTRICTED_COMMANDS`] and `extra`, nothing else.
+    pub fn restricted(extra: &[String]) -> Self {
+        let allowed = RESTRICTED_COMMANDS
+            .iter()
+            .map(|name| name.to_string())
+            .chain(extra.iter().map(|name| name.to_ascii_uppercase()))
+            .collect();
+        Self {
+            allowed: Some(allowed),
+        }
+    }
+
+    /// Whether this policy can refuse anything at all.
+    pub fn is_restricted(&self) -> bool {
+        self.allowed.is_some()
+    }
+
+    pub fn permits(&self, request: &Request) -> bool {
+        match (&self.allowed, request.command()) {
+            (Some(allowed), Some(command)) => allowed.contains(&command),
+            _ => true,
+        }
+    }
+}
+
+async fn write_all<W: AsyncWrite + Unpin>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(bytes).await?;
+    writer.flush().await
+}
+
+/// Relays an Assuan session between `client` and `server`, checking every
+/// line on the way.
+///
+/// Lines the client gets wrong and commands `policy` does not permit are
+/// answered with an `ERR` line instead of being forwarded; a malformed line
+/// from the server ends the session.
+pub async fn relay<C, S>(client: C, server: S, policy: &CommandPolicy) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite,
+    S: AsyncRead + AsyncWrite,
+{
+    let (client_r, mut client_w) = tokio::io::split(client);
+    let (server_r, mut server_w) = tokio::io::split(server);
+    let mut client_r = BufReader::new(client_r);
+    let mut server_r = BufReader::new(server_r);
+
+    // The server speaks first with its greeting.
+    answer(&mut server_r, &mut client_w, &mut client_r, &mut server_w).await?;
+
+    while let Some(line) = read_line(&mut client_r).await? {
+        let refusal = match Request::parse(&line) {
+            Ok(request) if policy.permits(&request) => None,
+            Ok(request) => {
+                tracing::info!(command = request.command(), "refused Assuan command");
+                Some(Response::error(GPG_ERR_FORBIDDEN, "Forbidden"))
+            }
+            Err(err) => {
+                tracing::debug!(%err, "malformed Assuan command");
+                Some(Response::error(GPG_ERR_ASS_SYNTAX, &err.to_string()))
+            }
+        };
+        if let Some(response) = refusal {
+            write_all(&mut client_w, &response.encode()).await?;
+            continue;
+        }
+
+        write_all(&mut server_w, &[&line[..], b"\n"].concat()).await?;
+        answer(&mut server_r, &mut client_w, &mut client_r, &mut server_w).await?;
+    }
+
+    Ok(())
+}
+
+/// Forwards the server's answer to one command, including any inquiries,
+/// up to and including the final `OK` or `ERR`.
+async fn answer<SR, CW, CR, SW>(
+    server_r: &mut SR,
+    client_w: &mut CW,
+    client_r: &mut CR,
+    server_w: &mut SW,
+) -> io::Result<()>
+where
+    SR: AsyncBufRead + Unpin,
+    CW: AsyncWrite + Unpin,
+    CR: AsyncBufRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    loop {
+        let line = read_line(server_r)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let response = Response::parse(&line)?;
+        write_all(client_w, &[&line[..], b"\n"].concat()).await?;
+
+        match response {
+            response if response.is_final() => return Ok(()),
+            Response::Inquire { .. } => inquiry(client_r, server_w).await?,
+            _ => {}
+        }
+    }
+}
+
+/// Forwards the client's data for an inquiry, up to `END` or `CAN`. A
+/// command in its place ends the session without reaching the server.
+async fn inquiry<CR, SW>(client_r: &mut CR, server_w: &mut SW) -> io::Result<()>
+where
+    CR: AsyncBufRead + Unpin,
+    SW: AsyncWrite + Unpin,
+{
+    loop {
+        let line = read_line(client_r)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        let request = Request::parse(&line)?;
+        if let Request::Command { name, .. } = request {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Assuan command {name} sent during an inquiry"),
+            ));
+        }
+        write_all(server_w, &[&line[..], b"\n"].concat()).await?;
+
+        if matches!(request, Request::End | Request::Cancel) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, duplex};
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!(
+            Request::parse(b"GETINFO  version").unwrap(),
+            Request::Command {
+                name: "GETINFO".into(),
+                args: b"version".to_vec()
+            }
+        );
+        assert_eq!(
+            Request::parse(b"D a%25b%0A c").unwrap(),
+            Request::Data(b"a%b\n c".to_vec())
+        );
+        assert_eq!(Request::parse(b"END").unwrap(), Request::End);
+        assert_eq!(Request::parse(b"CAN").unwrap(), Request::Cancel);
+        assert_eq!(
+            Request::parse(b"# hi").unwrap(),
+            Request::Comment(b" hi".to_vec())
+        );
+
+        assert_eq!(Request::parse(b""), Err(LineError::Empty));
+        assert_eq!(
+            Request::parse(b"GET/INFO"),
+            Err(LineError::InvalidCommand("GET/INFO".into()))
+        );
+        assert_eq!(Request::parse(b"D 100%"), Err(LineError::InvalidEscape));
+        assert_eq!(Request::parse(b"D %zz"), Err(LineError::InvalidEscape));
+    }
+
+    #[test]
+    fn parses_responses() {
+        assert_eq!(
+            Response::parse(b"OK Pleased to meet you").unwrap(),
+            Response::Ok(b"Pleased to meet you".to_vec())
+        );
+        assert_eq!(Response::parse(b"OK").unwrap(), Response::Ok(vec![]));
+        assert_eq!(
+            Response::parse(b"ERR 67108881 No secret key <GPG Agent>").unwrap(),
+            Response::Err {
+                code: 67108881,
+                description: b"No secret key <GPG Agent>".to_vec()
+            }
+        );
+        assert_eq!(
+            Response::parse(b"S PROGRESS primegen X 1 2").unwrap(),
+            Response::Status {
+                keyword: "PROGRESS".into(),
+                args: b"primegen X 1 2".to_vec()
+            }
+        );
+        assert_eq!(
+            Response::parse(b"INQUIRE PINENTRY_LAUNCHED 1234").unwrap(),
+            Response::Inquire {
+                keyword: "PINENTRY_LAUNCHED".into(),
+                args: b"1234".to_vec()
+            }
+        );
+        assert_eq!(Response::parse(b"END").unwrap(), Response::End);
+
+        assert_eq!(
+            Response::parse(b"ERR nope"),
+            Err(LineError::InvalidErrorCode)
+        );
+        assert_eq!(
+            Response::parse(b"INQUIRE"),
+            Err(LineError::MissingKeyword("INQUIRE"))
+        );
+        assert_eq!(
+            Response::parse(b"HELLO"),
+            Err(LineError::UnknownResponse("HELLO".into()))
+        );
+    }
+
+    #[test]
+    fn enforces_the_line_limit() {
+        let longest = [b'x'; MAX_LINE_LEN - 1];
+        assert!(Request::parse(&longest).is_ok());
+        assert_eq!(
+            Request::parse(&[b'x'; MAX_LINE_LEN]),
+            Err(LineError::TooLong)
+        );
+    }
+
+    #[test]
+    fn round_trips_and_splits_data() {
+        let data: Vec<u8> = (0..=255).cycle().take(5000).collect();
+        let encoded = Response::Data(data.clone()).encode();
+
+        let mut decoded = Vec::new();
+        for line in encoded.strip_suffix(b"\n").unwrap().split(|&b| b == b'\n') {
+            assert!(line.len() < MAX_LINE_LEN);
+            let Response::Data(chunk) = Response::parse(line).unwrap() else {
+                panic!("not a data line");
+            };
+            decoded.extend(chunk);
+        }
+        assert_eq!(decoded, data);
+
+        let error = Response::error(GPG_ERR_FORBIDDEN, "Forbidden");
+        assert_eq!(error.encode(), b"ERR 67109115 Forbidden <GPG Agent>\n");
+    }
+
+    #[tokio::test]
+    async fn reads_lines_up_to_the_limit() {
+        let input = [&b"OK\nD x\n"[..], &[b'y'; MAX_LINE_LEN], b"\n"].concat();
+        let mut reader = BufReader::with_capacity(16, &input[..]);
+
+        assert_eq!(read_line(&mut reader).await.unwrap(), Some(b"OK".to_vec()));
+        assert_eq!(read_line(&mut reader).await.unwrap(), Some(b"D x".to_vec()));
+        let err = read_line(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut truncated = BufReader::new(&b"OK"[..]);
+        let err = read_line(&mut truncated).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            read_line(&mut BufReader::new(&b""[..])).await.unwrap(),
+            None
+        );
+    }
+
+    /// Plays gpg-agent: greets, answers `GETINFO` and runs one inquiry.
+    async fn fake_agent(stream: tokio::io::DuplexStream) {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"OK Pleased to meet you\n").await.unwrap();
+
+        while let Some(line) = read_line(&mut reader).await.unwrap() {
+            let reply: &[u8] = match Request::parse(&line).unwrap().command().as_deref() {
+                Some("GETINFO") => b"D 2.4.5\nOK\n",
+                Some("PKSIGN") => {
+                    writer.write_all(b"INQUIRE HASH\n").await.unwrap();
+                    assert_eq!(read_line(&mut reader).await.unwrap().unwrap(), b"D abc");
+                    assert_eq!(read_line(&mut reader).await.unwrap().unwrap(), b"END");
+                    b"D sig\nOK\n"
+                }
+                _ => b"ERR 67109139 Unknown IPC command <GPG Agent>\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_a_session() {
+        let (agent_end, server) = duplex(4096);
+        let (mut client, relay_end) = duplex(4096);
+        tokio::spawn(fake_agent(agent_end));
+        let relay =
+            tokio::spawn(async move { relay(relay_end, server, &CommandPolicy::default()).await });
+
+        client
+            .write_all(b"GETINFO version\nNOT/VALID\nPKSIGN\nD abc\nEND\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut transcript = String::new();
+        client.read_to_string(&mut transcript).await.unwrap();
+        assert_eq!(
+            transcript,
+            "OK Pleased to meet you\n\
+             D 2.4.5\nOK\n\
+             ERR 67109140 Invalid Assuan command name \"NOT/VALID\" <GPG Agent>\n\
+             INQUIRE HASH\nD sig\nOK\n"
+        );
+        relay.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn restricted_policy_allows_signing_only() {
+        let policy = CommandPolicy::restricted(&["scd".into()]);
+        let permits = |line: &[u8]| policy.permits(&Request::parse(line).unwrap());
+
+        assert!(permits(b"pksign"));
+        assert!(permits(b"SETKEYDESC Please%20enter"));
+        assert!(permits(b"SCD SERIALNO"));
+        assert!(permits(b"D data"));
+        assert!(permits(b"END"));
+        assert!(permits(b"CAN"));
+        assert!(!permits(b"PRESET_PASSPHRASE abc -1 00"));
+        assert!(!permits(b"EXPORT_KEY ABCD"));
+        assert!(!permits(b"GET_PASSPHRASE x"));
+
+        assert!(CommandPolicy::default().permits(&Request::parse(b"KILLAGENT").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn restricted_relay_refuses_commands_locally() {
+        let (agent_end, server) = duplex(4096);
+        let (mut client, relay_end) = duplex(4096);
+        tokio::spawn(fake_agent(agent_end));
+        let policy = CommandPolicy::restricted(&[]);
+        let relay = tokio::spawn(async move { relay(relay_end, server, &policy).await });
+
+        client
+            .write_all(b"DELETE_KEY ABCD\nGETINFO version\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut transcript = String::new();
+        client.read_to_string(&mut transcript).await.unwrap();
+        assert_eq!(
+            transcript,
+            "OK Pleased to meet you\n\
+             ERR 67109115 Forbidden <GPG Agent>\n\
+             D 2.4.5\nOK\n"
+        );
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands_during_an_inquiry_are_not_forwarded() {
+        let mut client = BufReader::new(&b"D abc\nKILLAGENT\nEND\n"[..]);
+        let mut server = Vec::new();
+
+        let err = inquiry(&mut client, &mut server).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Assuan command KILLAGENT sent during an inquiry"
+        );
+        assert_eq!(server, b"D abc\n");
+    }
+}
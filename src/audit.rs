@@ -0,0 +1,465 @@
+//! An audit trail of the signatures WSL asks the Windows agent for.
+//!
+//! An [`Audited`] agent appends one JSON line per `SIGN_REQUEST` to an
+//! [`AuditLog`]: when it happened, which process asked, for which key and
+//! data, the host the connection is bound to (from OpenSSH's
+//! `session-bind@openssh.com`) and whether the agent signed.
+//!
+//! The log is append-only and rotated by size. A signature is only handed
+//! to the client once its record has been written. Relays sharing a log take
+//! turns through an advisory lock on `<path>.lock`.
+
+use crate::ssh_agent::{self, Agent, Request, Response, SessionBind};
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Default number of rotated files to keep.
+pub const DEFAULT_KEEP: u32 = 5;
+
+/// The WSL process a relay serves, as seen by the Unix socket listener.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Client {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    /// The process name, from `/proc/<pid>/comm`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+}
+
+impl Client {
+    /// Describes the peer of a Unix socket connection.
+    #[cfg(unix)]
+    pub fn of(stream: &tokio::net::UnixStream) -> io::Result<Client> {
+        let cred = stream.peer_cred()?;
+        let pid = cred.pid().and_then(|pid| u32::try_from(pid).ok());
+        let command = pid
+            .and_then(|pid| fs::read_to_string(format!("/proc/{pid}/comm")).ok())
+            .map(|comm| comm.trim_end().to_owned());
+        Ok(Client {
+            pid,
+            uid: Some(cred.uid()),
+            command,
+        })
+    }
+
+    /// The relay options that pass this client on.
+    #[cfg(unix)]
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(pid) = self.pid {
+            args.push(format!("--client-pid={pid}"));
+        }
+        if let Some(uid) = self.uid {
+            args.push(format!("--client-uid={uid}"));
+        }
+        if let Some(command) = &self.command {
+            args.push(format!("--client-command={command}"));
+        }
+        args
+    }
+}
+
+/// A size-rotated file of audit records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditLog {
+    pub path: PathBuf,
+    /// Rotate the log before it grows past this many bytes.
+    pub max_size: u64,
+    /// Rotated files to keep, as `<path>.1` (the newest) to `<path>.<keep>`.
+    pub keep: u32,
+    pub client: Client,
+}
+
+impl AuditLog {
+    /// Appends `record` as one line, rotating the log first if it would
+    /// grow past `max_size`.
+    ///
+    /// The file is opened for every record, so relays running side by side
+    /// all write to the current file after one of them rotated it. They
+    /// hold the lock file while checking the size, rotating and appending,
+    /// so none of them rotates the log under another.
+    fn append(&self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record).map_err(io::Error::other)?;
+        line.push(b'\n');
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(with_suffix(&self.path, ".lock"))?;
+        lock.lock()?;
+
+        let size = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        if size > 0 && size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)
+    }
+
+    /// Shifts `<path>.N` to `<path>.N+1`, dropping the oldest, and moves the
+    /// current log to `<path>.1`.
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return remove_if_exists(&self.path);
+        }
+
+        remove_if_exists(&rotated(&self.path, self.keep))?;
+        for n in (1..self.keep).rev() {
+            rename_if_exists(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+        }
+        rename_if_exists(&self.path, &rotated(&self.path, 1))
+    }
+}
+
+fn rotated(path: &Path, n: u32) -> PathBuf {
+    with_suffix(path, &format!(".{n}"))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// One line of the audit log.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    time: String,
+    client: &'a Client,
+    /// `SHA256:...` fingerprint of the key asked to sign.
+    key: String,
+    key_type: Option<String>,
+    flags: u32,
+    data_len: usize,
+    /// Fingerprint of the host key the connection was bound to, if any.
+    host_key: Option<String>,
+    forwarded: Option<bool>,
+    result: Outcome,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    /// The agent returned a signature.
+    Signed,
+    /// The agent, or a local policy, refused.
+    Failed,
+    /// The connection to the agent failed.
+    Error,
+}
+
+/// An agent whose sign requests are recorded in an [`AuditLog`].
+///
+/// Without a log every request is passed straight through.
+pub struct Audited<A> {
+    agent: A,
+    log: Option<AuditLog>,
+    /// The most recent successful session bind on this connection.
+    bound: Option<SessionBind>,
+}
+
+impl<A: Agent + Send> Audited<A> {
+    pub fn new(agent: A, log: Option<AuditLog>) -> Self {
+        Self {
+            agent,
+            log,
+            bound: None,
+        }
+    }
+}
+
+impl<A: Agent + Send> Agent for Audited<A> {
+    async fn call(&mut self, request: Request) -> io::Result<Response> {
+        let Some(log) = &self.log else {
+            return self.agent.call(request).await;
+        };
+
+        match &request {
+            Request::Extension { name, contents } if name == ssh_agent::SESSION_BIND => {
+                let bind = SessionBind::decode(contents).ok();
+                let response = self.agent.call(request).await?;
+                if let (Some(bind), Response::Success { .. }) = (bind, &response) {
+                    self.bound = Some(bind);
+                }
+                Ok(response)
+            }
+            Request::SignRequest {
+                key_blob,
+                data,
+                flags,
+            } => {
+                let mut record = Record {
+                    time: timestamp(SystemTime::now()),
+                    client: &log.client,
+                    key: ssh_agent::fingerprint(key_blob),
+                    key_type: ssh_agent::key_type(key_blob).map(str::to_owned),
+                    flags: *flags,
+                    data_len: data.len(),
+                    host_key: self
+                        .bound
+                        .as_ref()
+                        .map(|bind| ssh_agent::fingerprint(&bind.host_key)),
+                    forwarded: self.bound.as_ref().map(|bind| bind.forwarding),
+                    result: Outcome::Error,
+                };
+                let response = self.agent.call(request).await;
+                record.result = match &response {
+                    Ok(Response::SignResponse { .. }) => Outcome::Signed,
+                    Ok(_) => Outcome::Failed,
+                    Err(_) => Outcome::Error,
+                };
+                log.append(&record).map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("Failed to write audit log {}: {err}", log.path.display()),
+                    )
+                })?;
+                response
+            }
+            _ => self.agent.call(request).await,
+        }
+    }
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with milliseconds.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses a size such as `10M`, `512K`, `1G` or a plain number of bytes.
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {text:?}, expected e.g. 512K or 10M"))?;
+
+    let factor = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(format!("invalid size unit in {text:?}, expected K, M or G")),
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| format!("size {text:?} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::testing::{FakeAgent, identity, key, sign};
+    use crate::testing;
+    use std::time::Duration;
+
+    fn log_path(name: &str) -> PathBuf {
+        testing::temp_dir(name).join("audit.log")
+    }
+
+    fn records(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn records_sign_requests_with_their_session_bind() {
+        let path = log_path("audit");
+        let log = AuditLog {
+            path: path.clone(),
+            max_size: 10 << 20,
+            keep: DEFAULT_KEEP,
+            client: Client {
+                pid: Some(1234),
+                uid: Some(1000),
+                command: Some("ssh".into()),
+            },
+        };
+        let mut agent = Audited::new(FakeAgent::new(1, vec![identity(1, "a")]), Some(log));
+
+        assert_eq!(
+            agent.call(sign(&key(1))).await.unwrap(),
+            Response::SignResponse { signature: vec![1] }
+        );
+        let bind = SessionBind {
+            host_key: key(4),
+            session_id: vec![1; 32],
+            signature: vec![2; 64],
+            forwarding: false,
+        };
+        agent
+            .call(Request::Extension {
+                name: ssh_agent::SESSION_BIND.into(),
+                contents: bind.encode(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(agent.call(sign(&key(2))).await.unwrap(), Response::Failure);
+        agent.call(Request::RequestIdentities).await.unwrap();
+
+        let records = records(&path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0]["client"],
+            serde_json::json!({ "pid": 1234, "uid": 1000, "command": "ssh" })
+        );
+        assert_eq!(records[0]["key"], ssh_agent::fingerprint(&key(1)));
+        assert_eq!(records[0]["key_type"], "ssh-ed25519");
+        assert_eq!(records[0]["flags"], 2);
+        assert_eq!(records[0]["data_len"], 42);
+        assert_eq!(records[0]["host_key"], serde_json::Value::Null);
+        assert_eq!(records[0]["result"], "signed");
+
+        assert_eq!(records[1]["host_key"], ssh_agent::fingerprint(&key(4)));
+        assert_eq!(records[1]["forwarded"], false);
+        assert_eq!(records[1]["result"], "failed");
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let path = log_path("rotate");
+        let log = AuditLog {
+            path: path.clone(),
+            max_size: 250,
+            keep: 2,
+            client: Client::default(),
+        };
+        let record = Record {
+            time: timestamp(UNIX_EPOCH),
+            client: &log.client,
+            key: ssh_agent::fingerprint(b"key"),
+            key_type: None,
+            flags: 0,
+            data_len: 0,
+            host_key: None,
+            forwarded: None,
+            result: Outcome::Signed,
+        };
+
+        // Each record is a bit over 150 bytes, so every one starts a new file.
+        for _ in 0..4 {
+            log.append(&record).unwrap();
+        }
+        for path in [&path, &rotated(&path, 1), &rotated(&path, 2)] {
+            assert_eq!(records(path).len(), 1, "{}", path.display());
+        }
+        assert!(!rotated(&path, 3).exists());
+    }
+
+    #[test]
+    fn writers_take_turns_rotating() {
+        let path = log_path("writers");
+        let log = AuditLog {
+            path: path.clone(),
+            max_size: 1000,
+            keep: 100,
+            client: Client::default(),
+        };
+
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                // Each writer opens the lock file itself, like another relay.
+                scope.spawn(|| {
+                    let record = Record {
+                        time: timestamp(UNIX_EPOCH),
+                        client: &log.client,
+                        key: ssh_agent::fingerprint(b"key"),
+                        key_type: None,
+                        flags: 0,
+                        data_len: 0,
+                        host_key: None,
+                        forwarded: None,
+                        result: Outcome::Signed,
+                    };
+                    for _ in 0..25 {
+                        log.append(&record).unwrap();
+                    }
+                });
+            }
+        });
+
+        let files: Vec<_> = std::iter::once(path.clone())
+            .chain((1..=log.keep).map(|n| rotated(&path, n)))
+            .filter(|path| path.exists())
+            .collect();
+        for file in &files {
+            assert!(fs::metadata(file).unwrap().len() <= log.max_size);
+        }
+        let total: usize = files.iter().map(|file| records(file).len()).sum();
+        assert_eq!(total, 8 * 25);
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("10MB").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+}
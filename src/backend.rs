@@ -0,0 +1,275 @@
+//! Where listeners relay accepted connections to: a relay process started
+//! for each connection, or a stream multiplexed over one long-running
+//! `serve` process (see [`crate::mux`]) reached through a [`Transport`].
+//!
+//! The Unix socket listener uses this to reach Windows relays through WSL
+//! interop, and the named pipe server to reach Linux relays through
+//! `wsl.exe`.
+
+use crate::{
+    endpoint::{self, ChildIo, Stream},
+    mux::{Mux, MuxStream, Side},
+    relay::Relay,
+};
+use std::{io, path::PathBuf, pin::Pin};
+use tokio::{process::Command, sync::Mutex};
+
+/// Relay options a multiplexed stream may not use. With them, whoever can
+/// open streams could start programs or read and write files of their
+/// choosing.
+const STREAM_REFUSED_OPTIONS: &[&str] = &[
+    "--launch",
+    "--launch-command",
+    "--gpgconf",
+    "--preamble",
+    "--audit-log",
+    "--log-file",
+];
+
+/// The first option in the relay arguments `args` that a multiplexed stream
+/// may not use, if any.
+pub fn refused_stream_option(args: &[String]) -> Option<&'static str> {
+    args.iter().find_map(|arg| {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        STREAM_REFUSED_OPTIONS
+            .iter()
+            .find(|refused| **refused == name)
+            .copied()
+    })
+}
+
+/// A channel to a `serve` process being opened.
+pub type Connecting<'a> = Pin<Box<dyn Future<Output = io::Result<Box<dyn Stream>>> + Send + 'a>>;
+
+/// How a [`Server`] reaches a `serve` process.
+pub trait Transport: Send + Sync {
+    /// Opens a channel to a `serve` process, starting one if need be.
+    fn connect(&self) -> Connecting<'_>;
+}
+
+/// How to start a relay.
+#[derive(Clone, Debug)]
+pub struct RelayCommand {
+    pub exe: PathBuf,
+    pub args: Vec<String>,
+}
+
+impl RelayCommand {
+    /// Starts the relay with piped stdio, killing it if the handle is dropped.
+    ///
+    /// From WSL, `WSL_DISTRO_NAME` is shared with the Windows process, so it
+    /// can find WSL paths given as `--log-file`.
+    pub fn spawn(&self) -> io::Result<ChildIo> {
+        let mut command = Command::new(&self.exe);
+        command.args(&self.args);
+        #[cfg(unix)]
+        {
+            let wslenv = match std::env::var("WSLENV") {
+                Ok(wslenv) if !wslenv.is_empty() => format!("{wslenv}:WSL_DISTRO_NAME"),
+                _ => "WSL_DISTRO_NAME".into(),
+            };
+            command.env("WSLENV", wslenv);
+        }
+        endpoint::spawn(&mut command)
+    }
+}
+
+/// Starts the `serve` process over WSL interop (or, on Windows, `wsl.exe`)
+/// and talks to it over its stdio.
+impl Transport for RelayCommand {
+    fn connect(&self) -> Connecting<'_> {
+        Box::pin(async move {
+            tracing::info!(exe = %self.exe.display(), "starting serve process");
+            Ok(Box::new(self.spawn()?) as Box<dyn Stream>)
+        })
+    }
+}
+
+/// A long-running `serve` process shared by any number of connections.
+///
+/// The channel is opened on first use and opened again once it closes.
+pub struct Server {
+    transport: Box<dyn Transport>,
+    session: Mutex<Option<Mux>>,
+}
+
+impl Server {
+    /// A server reached through `transport`, e.g. a [`RelayCommand`] that
+    /// runs the `serve` mode.
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Box::new(transport),
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Opens a stream running the bridge described by `target`.
+    pub async fn open(&self, target: Vec<String>) -> io::Result<MuxStream> {
+        let mut session = self.session.lock().await;
+
+        let mux = match session.as_ref() {
+            Some(mux) if !mux.is_closed() => mux,
+            _ => {
+                let (reader, writer) = tokio::io::split(self.transport.connect().await?);
+                let (mux, _) = Mux::start(reader, writer, Side::Client);
+                session.insert(mux)
+            }
+        };
+
+        mux.open(target).await
+    }
+}
+
+/// Where accepted connections are relayed to.
+pub enum Backend {
+    /// Start a fresh relay process for every connection.
+    Spawn(RelayCommand),
+    /// Open a stream running `target` on a shared `serve` process.
+    Multiplexed {
+        server: std::sync::Arc<Server>,
+        target: Vec<String>,
+    },
+}
+
+impl Backend {
+    /// The arguments describing the bridge each connection is relayed to.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn args(&self) -> &[String] {
+        match self {
+            Backend::Spawn(command) => &command.args,
+            Backend::Multiplexed { target, .. } => target,
+        }
+    }
+
+    /// Relays `stream` until both sides are done, appending `extra_args` to
+    /// the bridge's arguments for this connection only.
+    pub async fn relay<S: Stream>(&self, stream: S, extra_args: &[String]) -> io::Result<()> {
+        match self {
+            Backend::Spawn(command) => {
+                let command = RelayCommand {
+                    exe: command.exe.clone(),
+                    args: [&command.args[..], extra_args].concat(),
+                };
+                let mut child = command.spawn()?;
+                if let Err(err) = Relay::new(stream, &mut child).run().await {
+                    // The child may still be waiting for input that will
+                    // never come.
+                    let _ = child.start_kill();
+                    let _ = child.wait().await;
+                    return Err(err.source);
+                }
+                child.wait().await?;
+                Ok(())
+            }
+            Backend::Multiplexed { server, target } => {
+                let upstream = server.open([&target[..], extra_args].concat()).await?;
+                Relay::new(stream, upstream)
+                    .run()
+                    .await
+                    .map(drop)
+                    .map_err(|err| err.source)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// A `serve` end in this process that answers every stream with its
+    /// target.
+    #[derive(Default)]
+    struct Loopback {
+        connects: Arc<AtomicUsize>,
+    }
+
+    impl Transport for Loopback {
+        fn connect(&self) -> Connecting<'_> {
+            self.connects.fetch_add(1, Ordering::Relaxed);
+            let (client, server) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let (reader, writer) = tokio::io::split(server);
+                let (_mux, mut incoming) = Mux::start(reader, writer, Side::Server);
+                while let Some(mut stream) = incoming.accept().await {
+                    let reply = stream.target.join(" ");
+                    stream.io.write_all(reply.as_bytes()).await.unwrap();
+                    stream.io.shutdown().await.unwrap();
+                }
+            });
+            Box::pin(async move { Ok(Box::new(client) as Box<dyn Stream>) })
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_streams_over_one_transport_channel() {
+        let transport = Loopback::default();
+        let connects = transport.connects.clone();
+        let server = Server::new(transport);
+
+        for target in ["pipe --name //./pipe/x", "tcp --address localhost:22"] {
+            let args = target.split(' ').map(String::from).collect();
+            let mut stream = server.open(args).await.unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).await.unwrap();
+            assert_eq!(reply, target);
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_relays_kill_the_process() {
+        use std::task::{Context, Poll};
+        use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+        /// A connection whose reads fail.
+        struct Failing;
+
+        impl AsyncRead for Failing {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                _: &mut ReadBuf<'_>,
+            ) -> Poll<io::Result<()>> {
+                Poll::Ready(Err(io::Error::other("read failed")))
+            }
+        }
+
+        impl AsyncWrite for Failing {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<io::Result<usize>> {
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let backend = Backend::Spawn(RelayCommand {
+            exe: "sh".into(),
+            args: vec!["-c".into(), "sleep 30".into()],
+        });
+
+        let relayed = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            backend.relay(Failing, &[]),
+        )
+        .await
+        .expect("relay process was not killed");
+        assert_eq!(relayed.unwrap_err().to_string(), "read failed");
+    }
+}
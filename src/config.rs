@@ -0,0 +1,1292 @@
+//! Declarative configuration for running many bridges from one daemon.
+//!
+//! ```toml
+//! exe = "/mnt/c/tools/wsl2-bridge-rs.exe"
+//!
+//! [bridges.ssh]
+//! kind = "ssh"
+//! source = "$XDG_RUNTIME_DIR/ssh-agent.sock"
+//! target = "//./pipe/openssh-ssh-agent"
+//! poll = true
+//!
+//! [bridges.gpg]
+//! kind = "gpg"
+//! source = "$XDG_RUNTIME_DIR/gnupg/S.gpg-agent"
+//! target = "S.gpg-agent"
+//!
+//! [bridges.gpg-ssh]
+//! kind = "gpg-ssh"
+//! source = "$XDG_RUNTIME_DIR/gnupg/S.gpg-agent.ssh"
+//! target = "S.gpg-agent.ssh"
+//!
+//! [bridges.postgres]
+//! kind = "tcp"
+//! source = "$XDG_RUNTIME_DIR/postgres.sock"
+//! target = "localhost:5432"
+//!
+//! [bridges.docker]
+//! kind = "unix"
+//! source = "$XDG_RUNTIME_DIR/docker-helper.sock"
+//! target = 'C:\Users\me\AppData\Local\docker-helper.sock'
+//! ```
+//!
+//! `source` is the Unix socket created inside WSL and `target` is what the
+//! Windows relay connects to. `~` and `$VAR`/`${VAR}` are expanded in paths.
+//!
+//! An optional `[relay_log]` table with `level`, `file` and `format` sets the
+//! logging options of the Windows relays.
+//!
+//! `vsock_port = 5000` carries the multiplexed streams over a Hyper-V socket
+//! to a Windows `serve --hvsock-port 5000` process started separately,
+//! instead of starting `exe serve` through WSL interop.
+
+use crate::{
+    audit, logging::LogFormat, policy::KeyFilter, retry, ssh_agent::Operation, tcp, unix_socket,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fmt, io,
+    path::{Path, PathBuf},
+};
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_EXE: &str = "/mnt/c/tools/wsl2-bridge-rs.exe";
+const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Invalid config: {0}")]
+    Parse(#[source] toml::de::Error),
+
+    #[error("Invalid config: {0}")]
+    Invalid(String),
+
+    #[error("Invalid config: bridge `{bridge}`: `{field}` {message}")]
+    InvalidField {
+        bridge: String,
+        field: &'static str,
+        message: String,
+    },
+}
+
+/// What a bridge connects to on the Windows side.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// A named pipe speaking the SSH agent protocol (`pipe` mode).
+    Ssh,
+    /// A GnuPG Assuan socket file (`gpg` mode).
+    Gpg,
+    /// gpg-agent's SSH agent socket file (`gpg-ssh` mode).
+    #[serde(rename = "gpg-ssh")]
+    GpgSsh,
+    /// A TCP service, as seen from Windows (`tcp` mode).
+    Tcp,
+    /// An AF_UNIX socket on the Windows side (`unix` mode).
+    Unix,
+}
+
+impl Kind {
+    /// Whether the target speaks the SSH agent protocol.
+    fn is_ssh_agent(self) -> bool {
+        matches!(self, Kind::Ssh | Kind::GpgSsh)
+    }
+
+    /// Whether the target is a socket file in the GnuPG socket directory.
+    fn is_socket_file(self) -> bool {
+        matches!(self, Kind::Gpg | Kind::GpgSsh)
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Ssh => f.write_str("ssh"),
+            Kind::Gpg => f.write_str("gpg"),
+            Kind::GpgSsh => f.write_str("gpg-ssh"),
+            Kind::Tcp => f.write_str("tcp"),
+            Kind::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    exe: Option<String>,
+    multiplex: Option<bool>,
+    vsock_port: Option<u32>,
+    #[serde(default)]
+    relay_log: RelayLog,
+    #[serde(default)]
+    bridges: BTreeMap<String, RawBridge>,
+}
+
+/// A `target` that is either one string or, for ssh bridges, a list of
+/// pipes whose agents are merged.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RawTarget {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBridge {
+    kind: Kind,
+    source: String,
+    target: RawTarget,
+    socket_mode: Option<u32>,
+    poll: Option<bool>,
+    allow: Option<Vec<Operation>>,
+    #[serde(default)]
+    key_fingerprint: Vec<String>,
+    #[serde(default)]
+    key_type: Vec<String>,
+    #[serde(default)]
+    key_comment: Vec<String>,
+    restricted: Option<bool>,
+    #[serde(default)]
+    allow_command: Vec<String>,
+    homedir: Option<String>,
+    launch: Option<bool>,
+    launch_command: Option<String>,
+    launch_timeout: Option<String>,
+    #[serde(default)]
+    retry: Retry,
+    idle_timeout: Option<String>,
+    session_timeout: Option<String>,
+    audit: Option<Audit>,
+    preamble: Option<String>,
+    connect_timeout: Option<String>,
+}
+
+/// How a bridge's relay retries connecting, passed on as `--retry-*`
+/// options. Unset fields keep the relay's defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    /// Durations are kept as written, e.g. `"50ms"`, once checked.
+    pub initial: Option<String>,
+    pub multiplier: Option<f64>,
+    pub max: Option<String>,
+    pub jitter: Option<f64>,
+    pub deadline: Option<String>,
+    pub attempts: Option<u32>,
+}
+
+impl Retry {
+    fn check(&self) -> Result<(), String> {
+        for duration in [&self.initial, &self.max, &self.deadline]
+            .into_iter()
+            .flatten()
+        {
+            retry::parse_duration(duration)?;
+        }
+        if let Some(multiplier) = self.multiplier {
+            retry::check_multiplier(multiplier)?;
+        }
+        if let Some(jitter) = self.jitter {
+            retry::check_jitter(jitter)?;
+        }
+        if self.attempts == Some(0) {
+            return Err("attempts must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let options = [
+            ("initial", self.initial.clone()),
+            ("multiplier", self.multiplier.map(|m| m.to_string())),
+            ("max", self.max.clone()),
+            ("jitter", self.jitter.map(|j| j.to_string())),
+            ("deadline", self.deadline.clone()),
+            ("attempts", self.attempts.map(|a| a.to_string())),
+        ];
+        for (option, value) in options {
+            if let Some(value) = value {
+                args.push(format!("--retry-{option}={value}"));
+            }
+        }
+        args
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// Windows relay executable, as seen from WSL.
+    pub exe: PathBuf,
+    /// Carry every connection over one `serve` process.
+    pub multiplex: bool,
+    /// Reach the `serve` process over this vsock port rather than starting it.
+    pub vsock_port: Option<u32>,
+    pub relay_log: RelayLog,
+    pub bridges: Vec<Bridge>,
+}
+
+/// Where a bridge's relay records sign requests, passed on as `--audit-*`
+/// options.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Audit {
+    /// Audit log, as a WSL or Windows path.
+    pub file: String,
+    /// Size such as `"10M"`, kept as written once checked.
+    pub max_size: Option<String>,
+    pub keep: Option<u32>,
+}
+
+impl Audit {
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![format!("--audit-log={}", self.file)];
+        if let Some(size) = &self.max_size {
+            args.push(format!("--audit-max-size={size}"));
+        }
+        if let Some(keep) = self.keep {
+            args.push(format!("--audit-keep={keep}"));
+        }
+        args
+    }
+}
+
+/// How the Windows relays log, passed on as `--log-*` options. Unset fields
+/// keep the relay's defaults.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RelayLog {
+    pub level: Option<String>,
+    /// Log file, as a WSL or Windows path.
+    pub file: Option<String>,
+    pub format: Option<LogFormat>,
+}
+
+impl RelayLog {
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(level) = &self.level {
+            args.push(format!("--log-level={level}"));
+        }
+        if let Some(file) = &self.file {
+            args.push(format!("--log-file={file}"));
+        }
+        match self.format {
+            Some(LogFormat::Json) => args.push("--log-format=json".into()),
+            Some(LogFormat::Text) => args.push("--log-format=text".into()),
+            None => {}
+        }
+        args
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bridge {
+    pub name: String,
+    pub kind: Kind,
+    /// Unix socket created inside WSL.
+    pub source: PathBuf,
+    /// Pipe names or socket name on the Windows side.
+    pub targets: Vec<String>,
+    pub socket_mode: u32,
+    /// Wait for the target to appear instead of failing.
+    pub poll: bool,
+    /// SSH agent operations to forward, or `None` for all of them.
+    pub allow: Option<Vec<Operation>>,
+    /// Keys to expose to clients.
+    pub keys: KeyFilter,
+    /// Only forward the Assuan commands of gpg-agent's extra socket.
+    pub restricted: bool,
+    /// Further Assuan commands to forward when `restricted`.
+    pub allow_commands: Vec<String>,
+    /// Windows path of the GnuPG home directory holding the socket files.
+    pub homedir: Option<String>,
+    /// Start gpg-agent if it is not running.
+    pub launch: bool,
+    /// Command that starts gpg-agent, if not the relay's default.
+    pub launch_command: Option<String>,
+    /// How long to wait for a launched gpg-agent, e.g. `"30s"`, if not the
+    /// relay's default.
+    pub launch_timeout: Option<String>,
+    pub retry: Retry,
+    /// End sessions without traffic for this long, e.g. `"10m"`.
+    pub idle_timeout: Option<String>,
+    /// End sessions that lasted this long, e.g. `"8h"`.
+    pub session_timeout: Option<String>,
+    /// Record sign requests in this audit log.
+    pub audit: Option<Audit>,
+    /// File whose contents are sent to a TCP service first.
+    pub preamble: Option<String>,
+    /// Give up on a TCP connection attempt after this long, e.g. `"5s"`.
+    pub connect_timeout: Option<String>,
+}
+
+impl Bridge {
+    /// Arguments for the Windows relay that connects this bridge's target.
+    pub fn relay_args(&self) -> Vec<String> {
+        let (mode, option) = match self.kind {
+            Kind::Ssh => ("pipe", "--name"),
+            Kind::Gpg => ("gpg", "--socket"),
+            Kind::GpgSsh => ("gpg-ssh", "--socket"),
+            Kind::Tcp => ("tcp", "--address"),
+            Kind::Unix => ("unix", "--path"),
+        };
+        let mut args = vec![mode.to_owned()];
+        for target in &self.targets {
+            args.extend([option.to_owned(), target.clone()]);
+        }
+        if self.poll {
+            args.push("--poll".into());
+        }
+        if let Some(allow) = &self.allow {
+            let allow: Vec<String> = allow.iter().map(Operation::to_string).collect();
+            args.push(format!("--allow={}", allow.join(",")));
+        }
+        for (option, values) in [
+            ("key-fingerprint", &self.keys.fingerprints),
+            ("key-type", &self.keys.types),
+            ("key-comment", &self.keys.comments),
+        ] {
+            args.extend(values.iter().map(|value| format!("--{option}={value}")));
+        }
+        if self.restricted {
+            args.push("--restricted".into());
+        }
+        for command in &self.allow_commands {
+            args.push(format!("--allow-command={command}"));
+        }
+        if let Some(homedir) = &self.homedir {
+            args.push(format!("--homedir={homedir}"));
+        }
+        if self.launch {
+            args.push("--launch".into());
+        }
+        if let Some(command) = &self.launch_command {
+            args.push(format!("--launch-command={command}"));
+        }
+        if let Some(timeout) = &self.launch_timeout {
+            args.push(format!("--launch-timeout={timeout}"));
+        }
+        if let Some(preamble) = &self.preamble {
+            args.push(format!("--preamble={preamble}"));
+        }
+        if let Some(timeout) = &self.connect_timeout {
+            args.push(format!("--connect-timeout={timeout}"));
+        }
+        args.extend(self.retry.args());
+        if let Some(timeout) = &self.idle_timeout {
+            args.push(format!("--idle-timeout={timeout}"));
+        }
+        if let Some(timeout) = &self.session_timeout {
+            args.push(format!("--session-timeout={timeout}"));
+        }
+        if let Some(audit) = &self.audit {
+            args.extend(audit.args());
+        }
+        args
+    }
+}
+
+/// `$XDG_CONFIG_HOME/wsl2-bridge/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home::home_dir()?.join(".config"),
+    };
+    Some(config_home.join("wsl2-bridge").join("config.toml"))
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        Config::parse(&text, |name| env::var(name).ok())
+    }
+
+    /// Parses and validates `text`, resolving variables through `var`.
+    pub fn parse(text: &str, var: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let raw: RawConfig = toml::from_str(text).map_err(ConfigError::Parse)?;
+
+        if raw.bridges.is_empty() {
+            return Err(ConfigError::Invalid("no bridges are defined".into()));
+        }
+
+        let exe = match raw.exe {
+            Some(exe) => expand(&exe, &var)
+                .map_err(|message| ConfigError::Invalid(format!("`exe` {message}")))?,
+            None => DEFAULT_EXE.into(),
+        };
+
+        let multiplex = raw.multiplex.unwrap_or(true);
+        if raw.vsock_port.is_some() && !multiplex {
+            return Err(ConfigError::Invalid(
+                "`vsock_port` carries multiplexed streams and needs `multiplex = true`".into(),
+            ));
+        }
+        if raw.vsock_port.is_some() && raw.relay_log != RelayLog::default() {
+            return Err(ConfigError::Invalid(
+                "`relay_log` cannot be used with `vsock_port`, give the logging options to the Windows `serve` process instead".into(),
+            ));
+        }
+
+        let mut relay_log = raw.relay_log;
+        if let Some(level) = &relay_log.level {
+            EnvFilter::try_new(level).map_err(|err| {
+                ConfigError::Invalid(format!("`relay_log.level` {level:?} is invalid: {err}"))
+            })?;
+        }
+        if let Some(file) = &relay_log.file {
+            relay_log.file =
+                Some(expand(file, &var).map_err(|message| {
+                    ConfigError::Invalid(format!("`relay_log.file` {message}"))
+                })?);
+        }
+
+        let vsock_port = raw.vsock_port;
+        let mut bridges: Vec<Bridge> = Vec::new();
+        for (name, raw) in raw.bridges {
+            let invalid = |field, message: String| ConfigError::InvalidField {
+                bridge: name.clone(),
+                field,
+                message,
+            };
+
+            let source = PathBuf::from(
+                expand(&raw.source, &var).map_err(|message| invalid("source", message))?,
+            );
+            if !source.is_absolute() {
+                return Err(invalid(
+                    "source",
+                    format!("must be an absolute path, got {}", source.display()),
+                ));
+            }
+            if let Some(other) = bridges.iter().find(|bridge| bridge.source == source) {
+                return Err(invalid(
+                    "source",
+                    format!(
+                        "{} is already used by bridge `{}`",
+                        source.display(),
+                        other.name
+                    ),
+                ));
+            }
+
+            let targets = match raw.target {
+                RawTarget::One(target) => vec![target],
+                RawTarget::Many(targets) => targets,
+            };
+            if targets.is_empty() || targets.iter().any(String::is_empty) {
+                return Err(invalid("target", "must not be empty".into()));
+            }
+            if targets.len() > 1 && raw.kind != Kind::Ssh {
+                return Err(invalid(
+                    "target",
+                    format!(
+                        "can only list several pipes for ssh bridges, not {}",
+                        raw.kind
+                    ),
+                ));
+            }
+
+            match raw.kind {
+                Kind::Tcp => {
+                    tcp::check_address(&targets[0])
+                        .map_err(|message| invalid("target", message))?;
+                }
+                Kind::Unix => {
+                    unix_socket::check_path(&targets[0])
+                        .map_err(|message| invalid("target", message))?;
+                }
+                _ => {}
+            }
+
+            let socket_mode = raw.socket_mode.unwrap_or(DEFAULT_SOCKET_MODE);
+            if socket_mode & !0o777 != 0 {
+                return Err(invalid(
+                    "socket_mode",
+                    format!("must be a permission mode like 0o600, got {socket_mode:#o}"),
+                ));
+            }
+
+            if raw.poll.is_some() && raw.kind != Kind::Ssh {
+                return Err(invalid(
+                    "poll",
+                    format!("is only supported by ssh bridges, not {}", raw.kind),
+                ));
+            }
+
+            if raw.allow.is_some() && !raw.kind.is_ssh_agent() {
+                return Err(invalid(
+                    "allow",
+                    format!(
+                        "is only supported by ssh and gpg-ssh bridges, not {}",
+                        raw.kind
+                    ),
+                ));
+            }
+            if raw.allow.as_ref().is_some_and(Vec::is_empty) {
+                return Err(invalid("allow", "must list at least one operation".into()));
+            }
+
+            let keys = KeyFilter {
+                fingerprints: raw.key_fingerprint,
+                types: raw.key_type,
+                comments: raw.key_comment,
+            };
+            if !keys.is_empty() && !raw.kind.is_ssh_agent() {
+                return Err(invalid(
+                    "key_*",
+                    format!(
+                        "filters are only supported by ssh and gpg-ssh bridges, not {}",
+                        raw.kind
+                    ),
+                ));
+            }
+
+            if raw.restricted.is_some() && raw.kind != Kind::Gpg {
+                return Err(invalid(
+                    "restricted",
+                    format!("is only supported by gpg bridges, not {}", raw.kind),
+                ));
+            }
+            let restricted = raw.restricted.unwrap_or(false);
+            if !raw.allow_command.is_empty() && !restricted {
+                return Err(invalid(
+                    "allow_command",
+                    "only applies with `restricted = true`".into(),
+                ));
+            }
+
+            if raw.homedir.is_some() && !raw.kind.is_socket_file() {
+                return Err(invalid(
+                    "homedir",
+                    format!(
+                        "is only supported by gpg and gpg-ssh bridges, not {}",
+                        raw.kind
+                    ),
+                ));
+            }
+
+            if raw.launch.is_some() && !raw.kind.is_socket_file() {
+                return Err(invalid(
+                    "launch",
+                    format!(
+                        "is only supported by gpg and gpg-ssh bridges, not {}",
+                        raw.kind
+                    ),
+                ));
+            }
+            let launch = raw.launch.unwrap_or(false);
+            if raw.launch_command.is_some() && !launch {
+                return Err(invalid(
+                    "launch_command",
+                    "only applies with `launch = true`".into(),
+                ));
+            }
+            if raw.launch_timeout.is_some() && !launch {
+                return Err(invalid(
+                    "launch_timeout",
+                    "only applies with `launch = true`".into(),
+                ));
+            }
+            if let Some(timeout) = &raw.launch_timeout {
+                retry::parse_duration(timeout)
+                    .map_err(|message| invalid("launch_timeout", message))?;
+            }
+
+            raw.retry
+                .check()
+                .map_err(|message| invalid("retry", message))?;
+            if let Some(timeout) = &raw.idle_timeout {
+                retry::parse_duration(timeout)
+                    .map_err(|message| invalid("idle_timeout", message))?;
+            }
+            if let Some(timeout) = &raw.session_timeout {
+                retry::parse_duration(timeout)
+                    .map_err(|message| invalid("session_timeout", message))?;
+            }
+
+            if raw.kind != Kind::Tcp {
+                for (field, value) in [
+                    ("preamble", &raw.preamble),
+                    ("connect_timeout", &raw.connect_timeout),
+                ] {
+                    if value.is_some() {
+                        return Err(invalid(
+                            field,
+                            format!("is only supported by tcp bridges, not {}", raw.kind),
+                        ));
+                    }
+                }
+            }
+            let preamble = match &raw.preamble {
+                Some(preamble) => {
+                    Some(expand(preamble, &var).map_err(|message| invalid("preamble", message))?)
+                }
+                None => None,
+            };
+            if let Some(timeout) = &raw.connect_timeout {
+                retry::parse_duration(timeout)
+                    .map_err(|message| invalid("connect_timeout", message))?;
+            }
+
+            let mut audit = raw.audit;
+            if let Some(audit) = &mut audit {
+                if !raw.kind.is_ssh_agent() {
+                    return Err(invalid(
+                        "audit",
+                        format!(
+                            "is only supported by ssh and gpg-ssh bridges, not {}",
+                            raw.kind
+                        ),
+                    ));
+                }
+                audit.file =
+                    expand(&audit.file, &var).map_err(|message| invalid("audit", message))?;
+                if let Some(size) = &audit.max_size {
+                    audit::parse_size(size).map_err(|message| invalid("audit", message))?;
+                }
+            }
+
+            // Without interop there is no relay process to fall back to for
+            // what multiplexed streams may not do.
+            if vsock_port.is_some() {
+                for (field, set) in [
+                    ("launch", launch),
+                    ("preamble", preamble.is_some()),
+                    ("audit", audit.is_some()),
+                ] {
+                    if set {
+                        return Err(invalid(
+                            field,
+                            "cannot be used with `vsock_port`, as multiplexed streams may not start programs or open files".into(),
+                        ));
+                    }
+                }
+            }
+
+            bridges.push(Bridge {
+                name,
+                kind: raw.kind,
+                source,
+                targets,
+                socket_mode,
+                poll: raw.poll.unwrap_or(false),
+                allow: raw.allow,
+                keys,
+                restricted,
+                allow_commands: raw.allow_command,
+                homedir: raw.homedir,
+                launch,
+                launch_command: raw.launch_command,
+                launch_timeout: raw.launch_timeout,
+                retry: raw.retry,
+                idle_timeout: raw.idle_timeout,
+                session_timeout: raw.session_timeout,
+                audit,
+                preamble,
+                connect_timeout: raw.connect_timeout,
+            });
+        }
+
+        Ok(Config {
+            exe: exe.into(),
+            multiplex,
+            vsock_port,
+            relay_log,
+            bridges,
+        })
+    }
+}
+
+/// Expands a leading `~/` and every `$NAME` or `${NAME}` in `value`.
+fn expand(value: &str, var: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = value;
+
+    if let Some(after) = rest.strip_prefix("~/") {
+        let home = var("HOME").ok_or("uses `~` but HOME is not set")?;
+        out.push_str(&home);
+        out.push('/');
+        rest = after;
+    }
+
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, after) = if let Some(braced) = rest.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("has an unterminated `${{` in {value:?}"))?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        if name.is_empty() {
+            return Err(format!("has a `$` without a variable name in {value:?}"));
+        }
+        let expanded = var(name).ok_or_else(|| format!("uses ${name}, which is not set"))?;
+        out.push_str(&expanded);
+        rest = after;
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "HOME" => Some("/home/me".into()),
+            "XDG_RUNTIME_DIR" => Some("/run/user/1000".into()),
+            _ => None,
+        }
+    }
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text, env)
+    }
+
+    #[test]
+    fn parses_multiple_bridges() {
+        let config = parse(
+            r#"
+            exe = "~/bin/wsl2-bridge-rs.exe"
+
+            [relay_log]
+            level = "debug"
+            file = "~/relay.log"
+            format = "json"
+
+            [bridges.ssh]
+            kind = "ssh"
+            source = "$XDG_RUNTIME_DIR/ssh-agent.sock"
+            target = ["//./pipe/openssh-ssh-agent", "//./pipe/1password"]
+            poll = true
+            allow = ["list", "sign"]
+            key_type = ["ssh-ed25519"]
+            key_comment = ["*@work"]
+            retry = { initial = "100ms", multiplier = 1.5, deadline = "30s" }
+            idle_timeout = "10m"
+
+            [bridges.gpg]
+            kind = "gpg"
+            source = "${XDG_RUNTIME_DIR}/gnupg/S.gpg-agent"
+            target = "S.gpg-agent"
+            socket_mode = 0o660
+            restricted = true
+            allow_command = ["scd"]
+            homedir = 'D:\gnupg'
+            launch = true
+            launch_timeout = "30s"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.exe, PathBuf::from("/home/me/bin/wsl2-bridge-rs.exe"));
+        assert!(config.multiplex);
+        assert_eq!(
+            config.relay_log.args(),
+            [
+                "--log-level=debug",
+                "--log-file=/home/me/relay.log",
+                "--log-format=json"
+            ]
+        );
+        assert_eq!(
+            config.bridges,
+            vec![
+                Bridge {
+                    name: "gpg".into(),
+                    kind: Kind::Gpg,
+                    source: "/run/user/1000/gnupg/S.gpg-agent".into(),
+                    targets: vec!["S.gpg-agent".into()],
+                    socket_mode: 0o660,
+                    poll: false,
+                    allow: None,
+                    keys: KeyFilter::default(),
+                    restricted: true,
+                    allow_commands: vec!["scd".into()],
+                    homedir: Some("D:\\gnupg".into()),
+                    launch: true,
+                    launch_command: None,
+                    launch_timeout: Some("30s".into()),
+                    retry: Retry::default(),
+                    idle_timeout: None,
+                    session_timeout: None,
+                    audit: None,
+                    preamble: None,
+                    connect_timeout: None,
+                },
+                Bridge {
+                    name: "ssh".into(),
+                    kind: Kind::Ssh,
+                    source: "/run/user/1000/ssh-agent.sock".into(),
+                    targets: vec![
+                        "//./pipe/openssh-ssh-agent".into(),
+                        "//./pipe/1password".into()
+                    ],
+                    socket_mode: 0o600,
+                    poll: true,
+                    allow: Some(vec![Operation::List, Operation::Sign]),
+                    keys: KeyFilter {
+                        types: vec!["ssh-ed25519".into()],
+                        comments: vec!["*@work".into()],
+                        ..KeyFilter::default()
+                    },
+                    restricted: false,
+                    allow_commands: vec![],
+                    homedir: None,
+                    launch: false,
+                    launch_command: None,
+                    launch_timeout: None,
+                    retry: Retry {
+                        initial: Some("100ms".into()),
+                        multiplier: Some(1.5),
+                        deadline: Some("30s".into()),
+                        ..Retry::default()
+                    },
+                    idle_timeout: Some("10m".into()),
+                    session_timeout: None,
+                    audit: None,
+                    preamble: None,
+                    connect_timeout: None,
+                },
+            ]
+        );
+        assert_eq!(
+            config.bridges[1].relay_args(),
+            [
+                "pipe",
+                "--name",
+                "//./pipe/openssh-ssh-agent",
+                "--name",
+                "//./pipe/1password",
+                "--poll",
+                "--allow=list,sign",
+                "--key-type=ssh-ed25519",
+                "--key-comment=*@work",
+                "--retry-initial=100ms",
+                "--retry-multiplier=1.5",
+                "--retry-deadline=30s",
+                "--idle-timeout=10m",
+            ]
+        );
+        assert_eq!(
+            config.bridges[0].relay_args(),
+            [
+                "gpg",
+                "--socket",
+                "S.gpg-agent",
+                "--restricted",
+                "--allow-command=scd",
+                "--homedir=D:\\gnupg",
+                "--launch",
+                "--launch-timeout=30s",
+            ]
+        );
+    }
+
+    #[test]
+    fn gpg_ssh_bridges_take_key_filters_and_homedir() {
+        let config = parse(
+            r#"
+            [bridges.gpg-ssh]
+            kind = "gpg-ssh"
+            source = "$XDG_RUNTIME_DIR/gnupg/S.gpg-agent.ssh"
+            target = "S.gpg-agent.ssh"
+            allow = ["list", "sign"]
+            key_type = ["ssh-ed25519"]
+            homedir = 'D:\gnupg'
+            audit = { file = "~/sign-audit.log", max_size = "1M" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.bridges[0].kind, Kind::GpgSsh);
+        assert_eq!(
+            config.bridges[0].relay_args(),
+            [
+                "gpg-ssh",
+                "--socket",
+                "S.gpg-agent.ssh",
+                "--allow=list,sign",
+                "--key-type=ssh-ed25519",
+                "--homedir=D:\\gnupg",
+                "--audit-log=/home/me/sign-audit.log",
+                "--audit-max-size=1M",
+            ]
+        );
+
+        let err = parse(
+            r#"
+            [bridges.gpg-ssh]
+            kind = "gpg-ssh"
+            source = "/tmp/S.gpg-agent.ssh"
+            target = "S.gpg-agent.ssh"
+            restricted = true
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `gpg-ssh`: `restricted` is only supported by gpg bridges, not gpg-ssh"
+        );
+    }
+
+    #[test]
+    fn tcp_bridges_take_an_address_and_preamble() {
+        let config = parse(
+            r#"
+            [bridges.license]
+            kind = "tcp"
+            source = "$XDG_RUNTIME_DIR/license.sock"
+            target = "localhost:27000"
+            preamble = "~/license-hello.bin"
+            connect_timeout = "5s"
+            retry = { attempts = 10 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.bridges[0].relay_args(),
+            [
+                "tcp",
+                "--address",
+                "localhost:27000",
+                "--preamble=/home/me/license-hello.bin",
+                "--connect-timeout=5s",
+                "--retry-attempts=10",
+            ]
+        );
+
+        let err = parse(
+            r#"
+            [bridges.license]
+            kind = "tcp"
+            source = "/tmp/license.sock"
+            target = "localhost"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `license`: `target` invalid address \"localhost\", expected HOST:PORT"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/openssh-ssh-agent"
+            connect_timeout = "5s"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `ssh`: `connect_timeout` is only supported by tcp bridges, not ssh"
+        );
+    }
+
+    #[test]
+    fn unix_bridges_take_a_socket_path() {
+        let config = parse(
+            r#"
+            [bridges.docker]
+            kind = "unix"
+            source = "/tmp/docker.sock"
+            target = 'C:\Users\me\docker.sock'
+            idle_timeout = "10m"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.bridges[0].relay_args(),
+            [
+                "unix",
+                "--path",
+                r"C:\Users\me\docker.sock",
+                "--idle-timeout=10m"
+            ]
+        );
+
+        let err = parse(&format!(
+            r#"
+            [bridges.docker]
+            kind = "unix"
+            source = "/tmp/docker.sock"
+            target = "/mnt/c/{}"
+            "#,
+            "x".repeat(101)
+        ))
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid config: bridge `docker`: `target` socket path "),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_point_at_the_problem() {
+        let err = parse(
+            r#"
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/a.sock"
+            target = "//./pipe/x"
+            pol = true
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("line 6"), "{err}");
+        assert!(err.contains("unknown field `pol`"), "{err}");
+
+        let err = parse(
+            r#"
+            [bridges.ssh]
+            kind = "telnet"
+            source = "/tmp/a.sock"
+            target = "x"
+            "#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("line 3"), "{err}");
+        assert!(err.contains("unknown variant `telnet`"), "{err}");
+    }
+
+    #[test]
+    fn semantic_errors_name_the_bridge_and_field() {
+        let err = parse(
+            r#"
+            [bridges.gpg]
+            kind = "gpg"
+            source = "/tmp/S.gpg-agent"
+            target = "S.gpg-agent"
+            poll = true
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `gpg`: `poll` is only supported by ssh bridges, not gpg"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.gpg]
+            kind = "gpg"
+            source = "/tmp/S.gpg-agent"
+            target = "S.gpg-agent"
+            audit = { file = "/tmp/audit.log" }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `gpg`: `audit` is only supported by ssh and gpg-ssh bridges, not gpg"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/openssh-ssh-agent"
+            audit = { file = "/tmp/audit.log", max_size = "10MB" }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `ssh`: `audit` invalid size unit in \"10MB\", expected K, M or G"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.gpg]
+            kind = "gpg"
+            source = "/tmp/S.gpg-agent"
+            target = "S.gpg-agent"
+            allow = ["sign"]
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `gpg`: `allow` is only supported by ssh and gpg-ssh bridges, not gpg"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.a]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/a"
+
+            [bridges.b]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/b"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `b`: `source` /tmp/agent.sock is already used by bridge `a`"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.gpg]
+            kind = "gpg"
+            source = "/tmp/S.gpg-agent"
+            target = "S.gpg-agent"
+            launch_command = "gpgconf --launch gpg-agent"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `gpg`: `launch_command` only applies with `launch = true`"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/openssh-ssh-agent"
+            retry = { max = "5 seconds" }
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `ssh`: `retry` invalid duration unit in \"5 seconds\", expected ms, s, m or h"
+        );
+
+        let err = parse(
+            r#"
+            relay_log = { level = "retry=loud" }
+
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/openssh-ssh-agent"
+            "#,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid config: `relay_log.level` \"retry=loud\" is invalid"),
+            "{err}"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.a]
+            kind = "ssh"
+            source = "$NOPE/agent.sock"
+            target = "//./pipe/a"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `a`: `source` uses $NOPE, which is not set"
+        );
+
+        let err = parse(
+            r#"
+            [bridges.a]
+            kind = "ssh"
+            source = "agent.sock"
+            target = "//./pipe/a"
+            "#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `a`: `source` must be an absolute path, got agent.sock"
+        );
+    }
+
+    #[test]
+    fn vsock_port_needs_the_relays_to_be_multiplexed() {
+        let bridge = r#"
+            [bridges.ssh]
+            kind = "ssh"
+            source = "/tmp/agent.sock"
+            target = "//./pipe/openssh-ssh-agent"
+        "#;
+        let config = parse(&format!("vsock_port = 5000\n{bridge}")).unwrap();
+        assert_eq!(config.vsock_port, Some(5000));
+        assert!(config.multiplex);
+
+        let err = parse(&format!("vsock_port = 5000\nmultiplex = false\n{bridge}")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: `vsock_port` carries multiplexed streams and needs `multiplex = true`"
+        );
+
+        let err = parse(&format!(
+            "vsock_port = 5000\nrelay_log = {{ level = \"debug\" }}\n{bridge}"
+        ))
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Invalid config: `relay_log` cannot be used with `vsock_port`"),
+            "{err}"
+        );
+
+        let err = parse(&format!(
+            "vsock_port = 5000\n{bridge}\naudit = {{ file = \"/tmp/audit.log\" }}"
+        ))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid config: bridge `ssh`: `audit` cannot be used with `vsock_port`, as multiplexed streams may not start programs or open files"
+        );
+    }
+
+    #[test]
+    fn requires_at_least_one_bridge() {
+        let err = parse("exe = \"/mnt/c/x.exe\"").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid config: no bridges are defined");
+    }
+
+    #[test]
+    fn expands_variables() {
+        assert_eq!(expand("~/a/$HOME/b", env).unwrap(), "/home/me/a//home/me/b");
+        assert_eq!(expand("${HOME}x", env).unwrap(), "/home/mex");
+        assert_eq!(expand("plain", env).unwrap(), "plain");
+        assert!(expand("${HOME", env).is_err());
+        assert!(expand("a$/b", env).is_err());
+    }
+}
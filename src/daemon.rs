@@ -0,0 +1,69 @@
+//! Runs every bridge from a [`Config`] in one process.
+
+use crate::{
+    backend::{self, Backend, RelayCommand, Server},
+    config::Config,
+    listen::{self, SocketListener},
+    vsock::{self, Vsock},
+};
+use std::{io, sync::Arc};
+use tokio::task::JoinSet;
+
+/// Binds every bridge's socket, then serves them all until SIGINT/SIGTERM.
+///
+/// All sockets are bound before anything is served so a bad bridge stops the
+/// daemon at startup rather than leaving it half running.
+pub async fn run(config: Config) -> io::Result<()> {
+    let log_args = config.relay_log.args();
+    let server = Arc::new(match config.vsock_port {
+        Some(port) => Server::new(Vsock {
+            cid: vsock::HOST_CID,
+            port,
+        }),
+        None => Server::new(RelayCommand {
+            exe: config.exe.clone(),
+            args: [vec!["serve".to_owned()], log_args.clone()].concat(),
+        }),
+    });
+    let shutdown = listen::shutdown_signal()?;
+
+    let mut listeners = Vec::new();
+    for bridge in &config.bridges {
+        let listener = SocketListener::bind(&bridge.source, bridge.socket_mode).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "bridge `{}`: {}: {err}",
+                    bridge.name,
+                    bridge.source.display()
+                ),
+            )
+        })?;
+        listeners.push(listener);
+    }
+
+    let mut bridges = JoinSet::new();
+    for (bridge, listener) in config.bridges.iter().zip(listeners) {
+        let target = bridge.relay_args();
+        // Streams may not launch gpg-agent, send a preamble or keep an audit
+        // log, so such bridges start a relay per connection instead.
+        let multiplexed = config.multiplex && backend::refused_stream_option(&target).is_none();
+        let backend = if multiplexed {
+            Backend::Multiplexed {
+                server: server.clone(),
+                target,
+            }
+        } else {
+            Backend::Spawn(RelayCommand {
+                exe: config.exe.clone(),
+                args: [target, log_args.clone()].concat(),
+            })
+        };
+        bridges.spawn(listen::accept_loop(listener, Arc::new(backend)));
+    }
+
+    tokio::select! {
+        Some(result) = bridges.join_next() => result.map_err(io::Error::other)?,
+        _ = shutdown => Ok(()),
+    }
+}
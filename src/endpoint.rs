@@ -0,0 +1,337 @@
+//! Endpoint addresses for the generic `relay` mode, in the style of socat.
+//!
+//! | Address                        | Endpoint                                  |
+//! |--------------------------------|-------------------------------------------|
+//! | `stdio:`                       | the relay's stdin/stdout                  |
+//! | `npipe://./pipe/NAME`          | a Windows named pipe                      |
+//! | `tcp://HOST:PORT`              | a TCP connection                          |
+//! | `unix:PATH`                    | a Unix socket                             |
+//! | `assuan:PATH`                  | the port named by a GnuPG socket file     |
+//! | `exec:COMMAND ARGS`            | a child process's stdin/stdout            |
+
+use crate::{launch, tcp};
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    str::FromStr,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+/// One side of a relay.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// `stdio:`. For a multiplexed stream, this is the stream.
+    Stdio,
+    /// `npipe://SERVER/pipe/NAME`, kept as `//SERVER/pipe/NAME`.
+    NamedPipe(String),
+    /// `tcp://HOST:PORT`, kept as `HOST:PORT`.
+    Tcp(String),
+    /// `unix:PATH`.
+    Unix(PathBuf),
+    /// `assuan:PATH`, a socket file written by libassuan's socket emulation,
+    /// such as gpg-agent's `S.gpg-agent` or `S.gpg-agent.ssh`.
+    Assuan(PathBuf),
+    /// `exec:COMMAND ARGS`, split like `--launch-command`.
+    Exec(Vec<String>),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(address: &str) -> Result<Endpoint, String> {
+        let (scheme, rest) = address.split_once(':').ok_or_else(|| {
+            format!("invalid address {address:?}, expected e.g. tcp://HOST:PORT or stdio:")
+        })?;
+        let missing = |what: &str| format!("address {address:?} has no {what}");
+
+        match scheme {
+            "stdio" if rest.is_empty() => Ok(Endpoint::Stdio),
+            "stdio" => Err(format!("address {address:?} takes nothing after `stdio:`")),
+            "npipe" => {
+                let path = rest.strip_prefix("//").ok_or_else(|| {
+                    format!("invalid address {address:?}, expected npipe://./pipe/NAME")
+                })?;
+                match path.split_once('/') {
+                    Some((server, name)) if !server.is_empty() => {
+                        match name.strip_prefix("pipe/") {
+                            Some("") => Err(missing("pipe name")),
+                            Some(_) => Ok(Endpoint::NamedPipe(rest.to_owned())),
+                            None => Err(format!(
+                                "invalid address {address:?}, expected npipe://{server}/pipe/NAME"
+                            )),
+                        }
+                    }
+                    _ => Err(format!(
+                        "invalid address {address:?}, expected npipe://./pipe/NAME"
+                    )),
+                }
+            }
+            "tcp" => {
+                let host_port = rest.strip_prefix("//").ok_or_else(|| {
+                    format!("invalid address {address:?}, expected tcp://HOST:PORT")
+                })?;
+                Ok(Endpoint::Tcp(tcp::check_address(host_port)?))
+            }
+            "unix" if rest.is_empty() => Err(missing("socket path")),
+            "unix" => Ok(Endpoint::Unix(rest.into())),
+            "assuan" if rest.is_empty() => Err(missing("socket file path")),
+            "assuan" => Ok(Endpoint::Assuan(rest.into())),
+            "exec" => {
+                let command = launch::split_command(rest);
+                if command.is_empty() {
+                    return Err(missing("command"));
+                }
+                Ok(Endpoint::Exec(command))
+            }
+            _ => Err(format!(
+                "unknown address type {scheme:?} in {address:?}, expected stdio, npipe, tcp, unix, assuan or exec"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Stdio => f.write_str("stdio:"),
+            Endpoint::NamedPipe(name) => write!(f, "npipe:{name}"),
+            Endpoint::Tcp(address) => write!(f, "tcp://{address}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Assuan(path) => write!(f, "assuan:{}", path.display()),
+            Endpoint::Exec(command) => write!(f, "exec:{}", command.join(" ")),
+        }
+    }
+}
+
+/// Anything a relay can carry bytes to and from.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Starts `command` with piped stdin and stdout, killing it if the handle
+/// is dropped.
+pub fn spawn(command: &mut Command) -> io::Result<ChildIo> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    Ok(ChildIo {
+        stdin: child.stdin.take(),
+        stdout: child.stdout.take().expect("stdout is piped"),
+        child,
+    })
+}
+
+/// A child process's stdin/stdout as a single relay endpoint.
+///
+/// Shutting down the write side closes the child's stdin, which is how the
+/// child learns that our peer has finished sending.
+pub struct ChildIo {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl ChildIo {
+    /// Waits for the child to exit once the relay is finished with it.
+    pub async fn wait(mut self) -> io::Result<std::process::ExitStatus> {
+        drop(self.stdin.take());
+        self.child.wait().await
+    }
+
+    /// Kills the child without waiting for it to exit.
+    pub fn start_kill(&mut self) -> io::Result<()> {
+        self.child.start_kill()
+    }
+}
+
+impl AsyncRead for ChildIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChildIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.stdin.as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(stdin) = self.stdin.as_mut() {
+            std::task::ready!(Pin::new(stdin).poll_flush(cx))?;
+        }
+        self.stdin = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(address: &str) -> Result<Endpoint, String> {
+        address.parse()
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse("stdio:"), Ok(Endpoint::Stdio));
+        assert_eq!(
+            parse("npipe://./pipe/openssh-ssh-agent"),
+            Ok(Endpoint::NamedPipe("//./pipe/openssh-ssh-agent".into()))
+        );
+        assert_eq!(
+            parse("npipe://server/pipe/a/b"),
+            Ok(Endpoint::NamedPipe("//server/pipe/a/b".into()))
+        );
+        assert_eq!(
+            parse("tcp://localhost:5432"),
+            Ok(Endpoint::Tcp("localhost:5432".into()))
+        );
+        assert_eq!(
+            parse("tcp://[::1]:22"),
+            Ok(Endpoint::Tcp("[::1]:22".into()))
+        );
+        assert_eq!(
+            parse("unix:/run/user/1000/agent.sock"),
+            Ok(Endpoint::Unix("/run/user/1000/agent.sock".into()))
+        );
+        assert_eq!(
+            parse("unix:rel.sock"),
+            Ok(Endpoint::Unix("rel.sock".into()))
+        );
+        assert_eq!(
+            parse(r"assuan:C:\Users\me\AppData\Local\gnupg\S.gpg-agent"),
+            Ok(Endpoint::Assuan(
+                r"C:\Users\me\AppData\Local\gnupg\S.gpg-agent".into()
+            ))
+        );
+        assert_eq!(
+            parse(r#"exec:"C:\Program Files\x.exe" --flag  a"#),
+            Ok(Endpoint::Exec(vec![
+                r"C:\Program Files\x.exe".into(),
+                "--flag".into(),
+                "a".into()
+            ]))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        let err = |address| parse(address).unwrap_err();
+
+        assert_eq!(
+            err("localhost:22"),
+            "unknown address type \"localhost\" in \"localhost:22\", expected stdio, npipe, tcp, unix, assuan or exec"
+        );
+        assert_eq!(
+            err("stdio"),
+            "invalid address \"stdio\", expected e.g. tcp://HOST:PORT or stdio:"
+        );
+        assert_eq!(
+            err("stdio:x"),
+            "address \"stdio:x\" takes nothing after `stdio:`"
+        );
+        assert_eq!(
+            err("STDIO:"),
+            "unknown address type \"STDIO\" in \"STDIO:\", expected stdio, npipe, tcp, unix, assuan or exec"
+        );
+
+        assert_eq!(
+            err("npipe:./pipe/x"),
+            "invalid address \"npipe:./pipe/x\", expected npipe://./pipe/NAME"
+        );
+        assert_eq!(
+            err("npipe://./x"),
+            "invalid address \"npipe://./x\", expected npipe://./pipe/NAME"
+        );
+        assert_eq!(
+            err("npipe:///pipe/x"),
+            "invalid address \"npipe:///pipe/x\", expected npipe://./pipe/NAME"
+        );
+        assert_eq!(
+            err("npipe://./pipe/"),
+            "address \"npipe://./pipe/\" has no pipe name"
+        );
+        assert_eq!(
+            err("npipe://./pipes"),
+            "invalid address \"npipe://./pipes\", expected npipe://./pipe/NAME"
+        );
+
+        assert_eq!(
+            err("tcp:localhost:22"),
+            "invalid address \"tcp:localhost:22\", expected tcp://HOST:PORT"
+        );
+        assert_eq!(
+            err("tcp://localhost"),
+            "invalid address \"localhost\", expected HOST:PORT"
+        );
+        assert_eq!(
+            err("tcp://localhost:99999"),
+            "invalid port \"99999\" in address \"localhost:99999\""
+        );
+
+        assert_eq!(err("unix:"), "address \"unix:\" has no socket path");
+        assert_eq!(
+            err("assuan:"),
+            "address \"assuan:\" has no socket file path"
+        );
+        assert_eq!(err("exec:  "), "address \"exec:  \" has no command");
+    }
+
+    #[test]
+    fn displays_addresses_as_parsed() {
+        for address in [
+            "stdio:",
+            "npipe://./pipe/openssh-ssh-agent",
+            "tcp://localhost:5432",
+            "unix:/tmp/agent.sock",
+            "assuan:/tmp/S.gpg-agent",
+            "exec:socat - TCP:localhost:22",
+        ] {
+            assert_eq!(parse(address).unwrap().to_string(), address);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn relays_through_child_processes() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut child =
+            spawn(Command::new("sh").args(["-c", "read line; echo \"reply $line\""])).unwrap();
+        child.write_all(b"hello\n").await.unwrap();
+        child.shutdown().await.unwrap();
+
+        let mut reply = String::new();
+        child.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "reply hello\n");
+        assert!(child.wait().await.unwrap().success());
+    }
+}
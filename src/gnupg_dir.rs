@@ -0,0 +1,283 @@
+//! Finding the directory gpg-agent puts its socket files in.
+//!
+//! The first of these that is available wins:
+//!
+//! 1. `--homedir`, as given on the command line.
+//! 2. The `GNUPGHOME` environment variable.
+//! 3. `socketdir` as reported by `gpgconf --list-dirs`.
+//! 4. `%LOCALAPPDATA%\gnupg`, where Gpg4win keeps its sockets by default.
+//! 5. `AppData\Local\gnupg` in the user's profile.
+//!
+//! A non-default home directory also holds the sockets, so the first two are
+//! used as they are, and `gpgconf` is only started when neither is set. A
+//! `gpgconf` that is missing or fails is skipped.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+use tokio::process::Command;
+
+/// Where a socket directory came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    Homedir,
+    GnupgHome,
+    Gpgconf,
+    LocalAppData,
+    Profile,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Homedir => f.write_str("--homedir"),
+            Source::GnupgHome => f.write_str("GNUPGHOME"),
+            Source::Gpgconf => f.write_str("gpgconf"),
+            Source::LocalAppData => f.write_str("LOCALAPPDATA"),
+            Source::Profile => f.write_str("home directory"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocketDir {
+    pub path: PathBuf,
+    pub source: Source,
+}
+
+/// How to find the socket directory.
+#[derive(Clone, Debug)]
+pub struct Locator<'a> {
+    /// Explicit home directory, which takes precedence over everything.
+    pub homedir: Option<&'a Path>,
+    /// The `gpgconf` program to ask, or `None` to skip asking.
+    pub gpgconf: Option<&'a Path>,
+}
+
+impl Locator<'_> {
+    /// Resolves the socket directory, reading the environment through `var`.
+    pub async fn resolve(&self, var: impl Fn(&str) -> Option<OsString>) -> Option<SocketDir> {
+        let found = |path: PathBuf, source| Some(SocketDir { path, source });
+        let non_empty = |name| var(name).filter(|value| !value.is_empty());
+
+        if let Some(homedir) = self.homedir {
+            return found(homedir.to_owned(), Source::Homedir);
+        }
+        if let Some(home) = non_empty("GNUPGHOME") {
+            return found(home.into(), Source::GnupgHome);
+        }
+        if let Some(gpgconf) = self.gpgconf
+            && let Some(dir) = gpgconf_socketdir(gpgconf).await
+        {
+            return found(dir, Source::Gpgconf);
+        }
+        if let Some(local) = non_empty("LOCALAPPDATA") {
+            return found(PathBuf::from(local).join("gnupg"), Source::LocalAppData);
+        }
+        if let Some(home) = home::home_dir() {
+            let dir = home.join("AppData").join("Local").join("gnupg");
+            return found(dir, Source::Profile);
+        }
+
+        None
+    }
+}
+
+/// Socket directories reported by each `gpgconf` program so far. Its answer
+/// does not change while we run, and a `serve` process relays many
+/// connections, so each program is only started once.
+static GPGCONF_ANSWERS: LazyLock<Mutex<HashMap<PathBuf, Option<PathBuf>>>> =
+    LazyLock::new(Mutex::default);
+
+/// Asks `gpgconf --list-dirs` for the socket directory. A `gpgconf` that is
+/// missing or fails just has nothing to say.
+async fn gpgconf_socketdir(gpgconf: &Path) -> Option<PathBuf> {
+    if let Some(answer) = GPGCONF_ANSWERS.lock().unwrap().get(gpgconf) {
+        return answer.clone();
+    }
+
+    let output = match Command::new(gpgconf).arg("--list-dirs").output().await {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            tracing::debug!(gpgconf = %gpgconf.display(), "gpgconf not found");
+            return None;
+        }
+        Err(err) => {
+            tracing::warn!(gpgconf = %gpgconf.display(), %err, "failed to run gpgconf");
+            return None;
+        }
+    };
+    if !output.status.success() {
+        tracing::warn!(
+            gpgconf = %gpgconf.display(),
+            status = %output.status,
+            "gpgconf --list-dirs failed"
+        );
+        return None;
+    }
+
+    let answer =
+        list_dirs_value(&String::from_utf8_lossy(&output.stdout), "socketdir").map(PathBuf::from);
+    GPGCONF_ANSWERS
+        .lock()
+        .unwrap()
+        .insert(gpgconf.to_owned(), answer.clone());
+    answer
+}
+
+/// Finds `name` in `gpgconf --list-dirs` output, which has one `name:value`
+/// line per directory with `:` and `%` in values percent-escaped.
+fn list_dirs_value(output: &str, name: &str) -> Option<String> {
+    let value = output.lines().find_map(|line| {
+        let (key, value) = line.trim_end_matches('\r').split_once(':')?;
+        (key == name).then_some(value)
+    })?;
+    (!value.is_empty()).then(|| unescape(value))
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST_DIRS: &str = "sysconfdir:C%3a\\ProgramData\\GNU\\etc\\gnupg\r\n\
+                             homedir:C%3a\\Users\\me\\AppData\\Roaming\\gnupg\r\n\
+                             socketdir:C%3a\\Users\\me\\AppData\\Local\\gnupg\r\n";
+
+    #[test]
+    fn parses_list_dirs_output() {
+        assert_eq!(
+            list_dirs_value(LIST_DIRS, "socketdir").as_deref(),
+            Some("C:\\Users\\me\\AppData\\Local\\gnupg")
+        );
+        assert_eq!(list_dirs_value(LIST_DIRS, "agent-socket"), None);
+        assert_eq!(list_dirs_value("socketdir:\n", "socketdir"), None);
+        assert_eq!(unescape("100%25 %zz%"), "100% %zz%");
+    }
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<OsString> {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_the_documented_precedence() {
+        let vars = env(&[("GNUPGHOME", "/gnupghome"), ("LOCALAPPDATA", "/local")]);
+        let missing = Path::new("/nonexistent/gpgconf");
+
+        let explicit = Locator {
+            homedir: Some(Path::new("/explicit")),
+            gpgconf: Some(missing),
+        };
+        assert_eq!(
+            explicit.resolve(&vars).await,
+            Some(SocketDir {
+                path: "/explicit".into(),
+                source: Source::Homedir
+            })
+        );
+
+        let locator = Locator {
+            homedir: None,
+            gpgconf: Some(missing),
+        };
+        let dir = locator.resolve(&vars).await.unwrap();
+        assert_eq!(dir.source, Source::GnupgHome);
+        assert_eq!(dir.path, Path::new("/gnupghome"));
+
+        // Without GNUPGHOME and gpgconf, LOCALAPPDATA is next.
+        let dir = locator
+            .resolve(env(&[("GNUPGHOME", ""), ("LOCALAPPDATA", "/local")]))
+            .await
+            .unwrap();
+        assert_eq!(dir.source, Source::LocalAppData);
+        assert_eq!(dir.path, Path::new("/local/gnupg"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn asks_gpgconf_before_localappdata() {
+        let dir = crate::testing::temp_dir("gpgconf");
+        let gpgconf = dir.join("gpgconf");
+        // Written by another process: a file this one has open for writing
+        // could be inherited by a test forking meanwhile, and executing it
+        // would then fail with ETXTBSY.
+        let written = std::process::Command::new("sh")
+            .args([
+                "-c",
+                "printf '%s' \"$1\" > \"$2\" && chmod 755 \"$2\"",
+                "sh",
+            ])
+            .arg(
+                "#!/bin/sh\n[ \"$1\" = --list-dirs ] || exit 1\n\
+                 echo 'homedir:/home/me/.gnupg'\necho 'socketdir:/run/user/1000/gnupg%3aextra'\n",
+            )
+            .arg(&gpgconf)
+            .status()
+            .unwrap();
+        assert!(written.success());
+
+        let locator = Locator {
+            homedir: None,
+            gpgconf: Some(&gpgconf),
+        };
+        let found = locator
+            .resolve(env(&[("LOCALAPPDATA", "/local")]))
+            .await
+            .unwrap();
+        assert_eq!(
+            found,
+            SocketDir {
+                path: "/run/user/1000/gnupg:extra".into(),
+                source: Source::Gpgconf
+            }
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn skips_a_failing_gpgconf() {
+        let locator = Locator {
+            homedir: None,
+            gpgconf: Some(Path::new("false")),
+        };
+        let dir = locator
+            .resolve(env(&[("LOCALAPPDATA", "/local")]))
+            .await
+            .unwrap();
+        assert_eq!(dir.source, Source::LocalAppData);
+    }
+}
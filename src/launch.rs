@@ -0,0 +1,105 @@
+//! Starting gpg-agent when it is not running yet.
+//!
+//! GnuPG tools start the agent on demand, so running one of them (by default
+//! `gpg-connect-agent /bye`) is enough. The agent then needs a moment to
+//! write its socket files and start listening, so connecting is retried
+//! every [`LAUNCH_POLL`] for a while.
+
+use std::{io, process::Stdio, time::Duration};
+use tokio::process::Command;
+
+/// How often to look for the agent while it starts.
+pub const LAUNCH_POLL: Duration = Duration::from_millis(50);
+
+pub const DEFAULT_LAUNCH_COMMAND: &str = "gpg-connect-agent /bye";
+
+/// Splits a command line at whitespace, keeping `"double quoted"` parts
+/// together so Windows paths with spaces can be given.
+pub fn split_command(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+
+    for c in command.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Runs the launch command and waits for it to exit.
+///
+/// Its stdin and stdout are detached, as ours usually carry the relayed
+/// protocol.
+pub async fn run(command: &[String]) -> io::Result<()> {
+    let Some((program, args)) = command.split_first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "launch command is empty",
+        ));
+    };
+
+    let status = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(io::Error::other(format!("exited with {status}")));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_commands() {
+        assert_eq!(
+            split_command(DEFAULT_LAUNCH_COMMAND),
+            ["gpg-connect-agent", "/bye"]
+        );
+        assert_eq!(
+            split_command(r#" "C:\Program Files (x86)\GnuPG\bin\gpgconf.exe"  --launch gpg-agent"#),
+            [
+                r"C:\Program Files (x86)\GnuPG\bin\gpgconf.exe",
+                "--launch",
+                "gpg-agent"
+            ]
+        );
+        assert_eq!(split_command(r#"echo """#), ["echo", ""]);
+        assert!(split_command("  ").is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reports_failing_launch_commands() {
+        run(&split_command("true")).await.unwrap();
+        let err = run(&split_command("false")).await.unwrap_err();
+        assert!(err.to_string().starts_with("exited with"), "{err}");
+        let err = run(&[]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,259 @@
+//! Linux side of the bridge: a Unix socket listener that replaces
+//! `socat UNIX-LISTEN:...,fork EXEC:...`.
+//!
+//! Every accepted connection is relayed to a [`Backend`]: its own Windows
+//! relay process, started via WSL interop, or a stream multiplexed over one
+//! long-running `serve` process.
+
+use crate::{audit::Client, backend::Backend};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    signal::unix::{SignalKind, signal},
+};
+
+/// A bound Unix socket that is removed again when dropped.
+pub struct SocketListener {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl SocketListener {
+    /// Binds `path`, replacing a stale socket left behind by a previous run
+    /// and restricting access to `mode` (e.g. `0o600`).
+    pub fn bind(path: &Path, mode: u32) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+
+        remove_stale_socket(path)?;
+
+        // Sockets are created as 0777 & ~umask, so the socket is bound in a
+        // directory only we can enter and only moved to `path` once it has
+        // `mode`. Nobody else can connect in between.
+        let staging = staging_dir(path);
+        fs::DirBuilder::new().mode(0o700).create(&staging)?;
+        let staged = staging.join("s");
+        let bound = UnixListener::bind(&staged).and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&staging);
+
+        Ok(Self {
+            path: path.to_owned(),
+            listener: bound?,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<UnixStream> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(stream)
+    }
+}
+
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A directory next to `path` to bind its socket in, unique to this bind.
+/// Kept short, as socket paths are limited to 107 bytes.
+fn staging_dir(path: &Path) -> PathBuf {
+    static BINDS: AtomicU32 = AtomicU32::new(0);
+    let bind = BINDS.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}-{bind}", std::process::id()))
+}
+
+/// Removes `path` if it is a socket nobody is listening on any more.
+///
+/// Anything that is not a socket is left alone, and a live socket is an
+/// `AddrInUse` error rather than something to delete from under its owner.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is already being served", path.display()),
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(err) => Err(err),
+    }
+}
+
+/// Serves `socket` until SIGINT/SIGTERM, relaying each connection to `backend`.
+pub async fn listen(socket: &Path, mode: u32, backend: Backend) -> io::Result<()> {
+    let listener = SocketListener::bind(socket, mode)?;
+    let shutdown = shutdown_signal()?;
+
+    tokio::select! {
+        result = accept_loop(listener, Arc::new(backend)) => result,
+        _ = shutdown => Ok(()),
+    }
+}
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = sigint.recv() => {}
+            _ = sigterm.recv() => {}
+        }
+    })
+}
+
+/// Accepts connections on `listener` forever, relaying each one to `backend`.
+pub async fn accept_loop(listener: SocketListener, backend: Arc<Backend>) -> io::Result<()> {
+    loop {
+        let stream = listener.accept().await?;
+        let backend = backend.clone();
+        let socket = listener.path.clone();
+        tokio::spawn(async move {
+            tracing::debug!(socket = %socket.display(), "accepted connection");
+            if let Err(err) = serve_connection(stream, &backend).await {
+                tracing::warn!(socket = %socket.display(), %err, "connection failed");
+            }
+        });
+    }
+}
+
+/// Whether relays started with `args` keep an audit log, and so need to be
+/// told which process they serve.
+fn audits(args: &[String]) -> bool {
+    args.iter()
+        .any(|arg| arg == "--audit-log" || arg.starts_with("--audit-log="))
+}
+
+/// The relay options describing the peer of `stream`, if relays started
+/// with `args` keep an audit log.
+fn client_args(args: &[String], stream: &UnixStream) -> io::Result<Vec<String>> {
+    if !audits(args) {
+        return Ok(Vec::new());
+    }
+    Ok(Client::of(stream)?.args())
+}
+
+async fn serve_connection(stream: UnixStream, backend: &Backend) -> io::Result<()> {
+    let client_args = client_args(backend.args(), &stream)?;
+    backend.relay(stream, &client_args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::RelayCommand, testing};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn socket_path(name: &str) -> PathBuf {
+        testing::temp_dir(name).join("agent.sock")
+    }
+
+    #[tokio::test]
+    async fn binds_with_requested_mode_and_cleans_up() {
+        let path = socket_path("mode");
+        let listener = SocketListener::bind(&path, 0o600).unwrap();
+
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        // Nothing is left of the directory the socket was bound in.
+        let entries = fs::read_dir(path.parent().unwrap()).unwrap();
+        assert_eq!(entries.count(), 1);
+        // It is still served under its new name.
+        UnixStream::connect(&path).await.unwrap();
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_socket() {
+        let path = socket_path("stale");
+        let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        drop(stale);
+        assert!(path.exists());
+
+        SocketListener::bind(&path, 0o600).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_live_socket_and_other_files() {
+        let path = socket_path("live");
+        let _live = SocketListener::bind(&path, 0o600).unwrap();
+        let err = SocketListener::bind(&path, 0o600).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        let file = socket_path("file");
+        fs::write(&file, b"not a socket").unwrap();
+        let err = SocketListener::bind(&file, 0o600).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert!(file.exists());
+    }
+
+    #[tokio::test]
+    async fn describes_the_client_to_auditing_relays() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let args = ["pipe".to_owned(), "--name".into(), "x".into()];
+        assert!(client_args(&args, &stream).unwrap().is_empty());
+
+        let auditing = ["gpg-ssh".to_owned(), "--audit-log=/tmp/audit.log".into()];
+        let args = client_args(&auditing, &stream).unwrap();
+        assert!(args.contains(&format!("--client-pid={}", std::process::id())));
+        assert!(args.iter().any(|arg| arg.starts_with("--client-uid=")));
+        assert!(args.iter().any(|arg| arg.starts_with("--client-command=")));
+    }
+
+    #[tokio::test]
+    async fn relays_connection_through_child() {
+        let path = socket_path("child");
+        let listener = SocketListener::bind(&path, 0o600).unwrap();
+        let backend = Backend::Spawn(RelayCommand {
+            exe: "sh".into(),
+            args: vec!["-c".into(), "read line; echo \"reply $line\"".into()],
+        });
+
+        let server = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            serve_connection(stream, &backend).await.unwrap();
+        });
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client.write_all(b"hello\n").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "reply hello\n");
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+//! Log output for every mode.
+//!
+//! Stdout usually carries the relayed protocol and stderr often goes nowhere
+//! (under socat or WSL interop), so logs can be written to a file instead,
+//! as text or as one JSON object per line.
+
+use clap::ValueEnum;
+use std::{fs::OpenOptions, io, path::PathBuf, sync::Mutex};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Logging options, accepted by every mode.
+#[derive(Clone, Debug, clap::Args)]
+pub struct LogArgs {
+    /// Least severe level to log (error, warn, info, debug or trace), or
+    /// `tracing` filter directives like `wsl2_bridge_rs::retry=debug`.
+    #[arg(long, global = true, value_name = "LEVEL", default_value = "warn")]
+    pub log_level: String,
+    /// Append logs to this file instead of writing them to stderr. The
+    /// Windows relay also accepts WSL paths such as `/mnt/c/...`.
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+}
+
+impl LogArgs {
+    /// Installs the global subscriber.
+    pub fn init(&self) -> io::Result<()> {
+        let filter = EnvFilter::try_new(&self.log_level)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+        let Some(path) = &self.log_file else {
+            match self.log_format {
+                LogFormat::Text => builder.with_writer(io::stderr).init(),
+                LogFormat::Json => builder.json().with_writer(io::stderr).init(),
+            }
+            return Ok(());
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(host_path(path))
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Failed to open log file {}: {err}", path.display()),
+                )
+            })?;
+        let writer = Mutex::new(file);
+        match self.log_format {
+            LogFormat::Text => builder.with_ansi(false).with_writer(writer).init(),
+            LogFormat::Json => builder.json().with_writer(writer).init(),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+const LOG_OPTIONS: &[&str] = &["--log-level", "--log-file", "--log-format"];
+
+/// Splits relay arguments into the logging options and everything else.
+///
+/// A `serve` process logs for all of its streams, so logging options given
+/// for a multiplexed relay are passed to `serve` instead.
+#[cfg(unix)]
+pub fn split_log_args(args: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let mut log_args = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let name = arg.split_once('=').map_or(arg.as_str(), |(name, _)| name);
+        if !LOG_OPTIONS.contains(&name) {
+            rest.push(arg);
+        } else if name.len() == arg.len() {
+            log_args.push(arg);
+            log_args.extend(args.next());
+        } else {
+            log_args.push(arg);
+        }
+    }
+
+    (log_args, rest)
+}
+
+/// `path` as this process can open it.
+#[cfg(windows)]
+pub fn host_path(path: &std::path::Path) -> PathBuf {
+    let distro = std::env::var("WSL_DISTRO_NAME").ok();
+    path.to_str()
+        .and_then(|path| windows_path(path, distro.as_deref()))
+        .map_or_else(|| path.to_owned(), PathBuf::from)
+}
+
+#[cfg(not(windows))]
+pub fn host_path(path: &std::path::Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// Translates a WSL path to the Windows path of the same file: `/mnt/c/x`
+/// is `C:\x`, and other absolute paths are reached through
+/// `\\wsl.localhost\<distro>`.
+///
+/// Returns `None` for paths that are not WSL paths, or cannot be translated
+/// without the distro name.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn windows_path(path: &str, distro: Option<&str>) -> Option<String> {
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    if let Some(rest) = path.strip_prefix("/mnt/") {
+        let (drive, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        if drive.len() == 1 && drive.chars().all(|c| c.is_ascii_alphabetic()) {
+            let drive = drive.to_ascii_uppercase();
+            return Some(format!("{drive}:\\{}", rest.replace('/', "\\")));
+        }
+    }
+
+    Some(format!(
+        "\\\\wsl.localhost\\{}{}",
+        distro?,
+        path.replace('/', "\\")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_wsl_paths() {
+        let windows = |path| windows_path(path, Some("Ubuntu"));
+        assert_eq!(
+            windows("/mnt/c/Users/me/relay.log").as_deref(),
+            Some(r"C:\Users\me\relay.log")
+        );
+        assert_eq!(windows("/mnt/d").as_deref(), Some(r"D:\"));
+        assert_eq!(
+            windows("/home/me/relay.log").as_deref(),
+            Some(r"\\wsl.localhost\Ubuntu\home\me\relay.log")
+        );
+        assert_eq!(
+            windows("/mnt/wsl/relay.log").as_deref(),
+            Some(r"\\wsl.localhost\Ubuntu\mnt\wsl\relay.log")
+        );
+        assert_eq!(windows(r"C:\logs\relay.log"), None);
+        assert_eq!(windows_path("/tmp/relay.log", None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn splits_log_options_from_relay_arguments() {
+        let args = [
+            "pipe",
+            "--log-level",
+            "debug",
+            "--name",
+            "x",
+            "--log-file=/tmp/a b",
+        ];
+        let (log_args, rest) = split_log_args(args.map(String::from).to_vec());
+        assert_eq!(log_args, ["--log-level", "debug", "--log-file=/tmp/a b"]);
+        assert_eq!(rest, ["pipe", "--name", "x"]);
+    }
+}
@@ -1,10 +1,61 @@
-use clap::{Parser, Subcommand};
-use std::{num::ParseIntError, path::Path, time::Duration};
-use tokio::net::windows::named_pipe::NamedPipeClient;
+mod assuan;
+mod audit;
+mod backend;
+#[cfg(unix)]
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod endpoint;
+mod gnupg_dir;
+mod launch;
+#[cfg(unix)]
+mod listen;
+mod logging;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod merge;
+mod mux;
+#[cfg(windows)]
+mod pipe_server;
+mod policy;
+mod relay;
+mod retry;
+mod socket_file;
+mod ssh_agent;
+mod tcp;
+#[cfg(test)]
+mod testing;
+mod unix_socket;
+mod vsock;
+
+use audit::{AuditLog, Audited};
+use backend::{Backend, RelayCommand, Server};
+use clap::{Parser, Subcommand, ValueEnum};
+use endpoint::{Endpoint, Stream};
+use mux::{IncomingStream, Mux, Side};
+use policy::Policy;
+#[cfg(windows)]
+use relay::{HalfClose, PIPE_LINGER};
+use relay::{Relay, RelayError};
+use retry::RetryPolicy;
+use socket_file::{SocketFile, SocketFileError};
+#[cfg(unix)]
+use std::num::ParseIntError;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    io::{self as io, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufStream},
-    net::{TcpStream, windows::named_pipe::ClientOptions},
+    io::{self as io, AsyncRead, AsyncWrite, AsyncWriteExt, Join, Stdin, Stdout},
+    net::TcpStream,
 };
+use tracing::Instrument;
+
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+#[cfg(windows)]
 use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
 
 #[derive(Clone, Debug, Subcommand)]
@@ -12,13 +63,422 @@ enum Mode {
     Gpg {
         #[arg(short, long)]
         socket: String,
+        /// How to relay traffic to the socket.
+        #[arg(long, value_enum, default_value_t = GpgProtocol::Raw)]
+        protocol: GpgProtocol,
+        /// Only forward the commands needed to sign and decrypt with
+        /// existing keys, like gpg-agent's extra socket. Implies
+        /// `--protocol assuan`.
+        #[arg(long)]
+        restricted: bool,
+        /// Also forward this command when `--restricted`.
+        #[arg(long, value_name = "COMMAND", requires = "restricted")]
+        allow_command: Vec<String>,
+        #[command(flatten)]
+        dirs: GnupgDirArgs,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+    },
+    /// Relay SSH agent traffic to gpg-agent's SSH support socket
+    /// (`enable-ssh-support`).
+    GpgSsh {
+        #[arg(short, long, default_value = "S.gpg-agent.ssh")]
+        socket: String,
+        /// How to relay traffic to the socket.
+        #[arg(long, value_enum, default_value_t = SshProtocol::Raw)]
+        protocol: SshProtocol,
+        #[command(flatten)]
+        policy: PolicyArgs,
+        #[command(flatten)]
+        audit: AuditArgs,
+        #[command(flatten)]
+        dirs: GnupgDirArgs,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
     },
+    #[cfg(windows)]
     Pipe {
         #[arg(short, long)]
         poll: bool,
+        /// Pipe to connect to. Given more than once, the agents behind the
+        /// pipes are merged into one and `--protocol ssh-agent` is implied.
+        #[arg(short, long, required = true)]
+        name: Vec<String>,
+        /// How to relay traffic to the pipe.
+        #[arg(long, value_enum, default_value_t = SshProtocol::Raw)]
+        protocol: SshProtocol,
+        /// How long to keep relaying the agent's replies once the client
+        /// has finished sending and the pipe has gone quiet. Defaults to
+        /// 60s, so that confirmation prompts can be answered; shorten it
+        /// for clients that wait for the relay to exit.
+        #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+        linger: Option<Duration>,
+        #[command(flatten)]
+        policy: PolicyArgs,
+        #[command(flatten)]
+        audit: AuditArgs,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+    },
+    /// Relay to a TCP service, e.g. one that only listens on Windows'
+    /// localhost.
+    Tcp {
+        /// Address to connect to, as seen from Windows, e.g. `localhost:5432`.
+        #[arg(short, long, value_name = "HOST:PORT", value_parser = tcp::check_address)]
+        address: String,
+        /// Send the contents of this file before relaying, e.g. a greeting
+        /// the service expects. WSL paths work as for `--log-file`.
+        #[arg(long, value_name = "FILE")]
+        preamble: Option<PathBuf>,
+        /// Give up on a connection attempt after this long, e.g. `5s`.
+        #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+        connect_timeout: Option<Duration>,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+    },
+    /// Relay to a Unix socket, e.g. an AF_UNIX socket a Windows program
+    /// created on NTFS.
+    Unix {
+        /// Path of the socket, e.g. `C:\Users\me\agent.sock`. WSL paths work
+        /// as for `--log-file`.
+        #[arg(short, long, value_parser = unix_socket::check_path)]
+        path: PathBuf,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+    },
+    /// Relay between two endpoint addresses: `stdio:`,
+    /// `npipe://./pipe/NAME`, `tcp://HOST:PORT`, `unix:PATH`,
+    /// `assuan:PATH` (a GnuPG socket file) or `exec:COMMAND ARGS`.
+    Relay {
+        #[arg(value_name = "A", value_parser = Endpoint::from_str)]
+        a: Endpoint,
+        #[arg(value_name = "B", value_parser = Endpoint::from_str)]
+        b: Endpoint,
+        #[command(flatten)]
+        retry: RetryArgs,
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+    },
+    /// Relay any number of multiplexed streams over stdin/stdout. Streams
+    /// can only run the `pipe`, `gpg`, `gpg-ssh`, `tcp` and `unix` modes, or
+    /// `relay` without `exec:` endpoints.
+    Serve {
+        /// Serve connections from WSL on this Hyper-V socket (vsock) port
+        /// instead of stdin/stdout, e.g. for `listen --vsock-port`.
+        #[cfg(windows)]
+        #[arg(long, value_name = "PORT", requires = "vm_id")]
+        hvsock_port: Option<u32>,
+        /// Only accept connections from this VM, WSL's as listed by
+        /// `hcsdiag list`.
+        #[cfg(windows)]
+        #[arg(
+            long,
+            value_name = "GUID",
+            value_parser = vsock::parse_vm_id,
+            requires = "hvsock_port"
+        )]
+        vm_id: Option<u128>,
+    },
+    /// Serve a Unix socket, starting the Windows relay for each connection.
+    #[cfg(unix)]
+    Listen {
+        /// Path of the Unix socket to create.
+        #[arg(short, long)]
+        socket: PathBuf,
+        /// Permissions of the socket file, in octal.
+        #[arg(long, default_value = "600", value_parser = parse_octal)]
+        socket_mode: u32,
+        /// Windows relay executable, e.g. /mnt/c/tools/wsl2-bridge-rs.exe.
+        #[arg(short, long, required_unless_present = "vsock_port")]
+        exe: Option<PathBuf>,
+        /// Carry every connection over one long-running `<exe> serve`
+        /// process instead of starting the relay per connection.
+        #[arg(long)]
+        multiplex: bool,
+        /// Carry every connection over a Hyper-V socket to a Windows
+        /// `serve --hvsock-port PORT` process instead of WSL interop.
+        /// Implies `--multiplex`.
+        #[arg(long, value_name = "PORT", conflicts_with = "exe")]
+        vsock_port: Option<u32>,
+        /// Arguments passed to the relay, e.g. `-- pipe --name //./pipe/openssh-ssh-agent`.
+        #[arg(last = true, required = true)]
+        args: Vec<String>,
+    },
+    /// Serve a named pipe, relaying each connection to a Unix socket in
+    /// WSL through `wsl.exe`.
+    #[cfg(windows)]
+    PipeServer {
+        /// Name of the pipe to create, e.g. `//./pipe/wsl-ssh-agent`.
         #[arg(short, long)]
         name: String,
+        /// Unix socket to relay to, as seen from WSL, e.g.
+        /// `/home/me/.ssh/agent.sock`.
+        #[arg(short, long)]
+        socket: String,
+        /// WSL distribution to run the relay in, by default the default one.
+        #[arg(short, long)]
+        distribution: Option<String>,
+        /// Linux relay executable, as seen from WSL.
+        #[arg(short, long, default_value = "wsl2-bridge-rs")]
+        exe: String,
+        /// `wsl.exe` program used to start the relay.
+        #[arg(long, value_name = "PATH", default_value = "wsl.exe")]
+        wsl: PathBuf,
+        /// Carry every connection over one long-running `<exe> serve`
+        /// process instead of starting the relay per connection.
+        #[arg(long)]
+        multiplex: bool,
     },
+    /// Serve every bridge described in a config file.
+    #[cfg(unix)]
+    Daemon {
+        /// Config file, by default ~/.config/wsl2-bridge/config.toml.
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+        /// Only validate the config file.
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+/// Where `gpg` and `gpg-ssh` relays look for socket files; see [`gnupg_dir`] for the
+/// order in which these are tried.
+#[derive(Clone, Debug, clap::Args)]
+struct GnupgDirArgs {
+    /// GnuPG home directory holding the socket files.
+    #[arg(long, value_name = "DIR")]
+    homedir: Option<PathBuf>,
+    /// `gpgconf` program asked for the socket directory.
+    #[arg(long, value_name = "PATH", default_value = "gpgconf")]
+    gpgconf: PathBuf,
+    /// Start gpg-agent if it is not running, then wait for it.
+    #[arg(long)]
+    launch: bool,
+    /// Command that starts gpg-agent; `"double quotes"` keep a path with
+    /// spaces together.
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        default_value = launch::DEFAULT_LAUNCH_COMMAND,
+        requires = "launch"
+    )]
+    launch_command: String,
+    /// How long to wait for a launched gpg-agent, e.g. `30s`.
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = retry::parse_duration,
+        default_value = "10s",
+        requires = "launch"
+    )]
+    launch_timeout: Duration,
+}
+
+/// How to retry connecting. Options that are not given keep the defaults
+/// of the mode.
+#[derive(Clone, Debug, clap::Args)]
+struct RetryArgs {
+    /// Delay after the first failed attempt, e.g. `50ms`.
+    #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+    retry_initial: Option<Duration>,
+    /// Factor the delay grows by after every further attempt.
+    #[arg(long, value_name = "FACTOR", value_parser = retry::parse_multiplier)]
+    retry_multiplier: Option<f64>,
+    /// Longest delay between attempts.
+    #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+    retry_max: Option<Duration>,
+    /// Fraction by which delays are randomly varied, between 0 and 1.
+    #[arg(long, value_name = "FRACTION", value_parser = retry::parse_jitter)]
+    retry_jitter: Option<f64>,
+    /// Give up after this long, e.g. `30s`.
+    #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+    retry_deadline: Option<Duration>,
+    /// Give up after this many attempts.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    retry_attempts: Option<u32>,
+}
+
+impl RetryArgs {
+    fn policy(self, defaults: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            initial: self.retry_initial.unwrap_or(defaults.initial),
+            multiplier: self.retry_multiplier.unwrap_or(defaults.multiplier),
+            max_delay: self.retry_max.unwrap_or(defaults.max_delay),
+            jitter: self.retry_jitter.unwrap_or(defaults.jitter),
+            deadline: self.retry_deadline.or(defaults.deadline),
+            max_attempts: self.retry_attempts.or(defaults.max_attempts),
+        }
+    }
+}
+
+/// When to end a session. Either timeout ends the relay with exit status
+/// [`TIMEOUT_EXIT_CODE`].
+#[derive(Clone, Debug, clap::Args)]
+struct TimeoutArgs {
+    /// End the session after this long without traffic, e.g. `10m`. Like
+    /// `--session-timeout`, this counts from when the relay has connected.
+    #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+    idle_timeout: Option<Duration>,
+    /// End the session after this long, busy or not, e.g. `8h`.
+    #[arg(long, value_name = "DURATION", value_parser = retry::parse_duration)]
+    session_timeout: Option<Duration>,
+}
+
+impl TimeoutArgs {
+    fn timeouts(&self) -> relay::Timeouts {
+        relay::Timeouts {
+            idle: self.idle_timeout,
+            session: self.session_timeout,
+        }
+    }
+}
+
+/// Exit status when a session timed out, as with `timeout(1)`.
+const TIMEOUT_EXIT_CODE: u8 = 124;
+
+/// Retrying a busy pipe, or one that does not exist yet with `--poll`. By
+/// default this goes on for as long as it takes.
+#[cfg(windows)]
+const PIPE_RETRY: RetryPolicy = RetryPolicy {
+    initial: Duration::from_millis(50),
+    multiplier: 2.0,
+    max_delay: Duration::from_secs(1),
+    jitter: 0.1,
+    deadline: None,
+    max_attempts: None,
+};
+
+/// Reading a socket file again while it names an agent that is gone, as
+/// happens when gpg-agent restarts between reading and connecting.
+const STALE_RETRY: RetryPolicy = RetryPolicy {
+    initial: Duration::from_millis(50),
+    multiplier: 2.0,
+    max_delay: Duration::from_secs(1),
+    jitter: 0.1,
+    deadline: None,
+    max_attempts: Some(5),
+};
+
+/// Retrying a `tcp` target that refuses connections or does not answer in
+/// time.
+const TCP_RETRY: RetryPolicy = RetryPolicy {
+    initial: Duration::from_millis(50),
+    multiplier: 2.0,
+    max_delay: Duration::from_secs(1),
+    jitter: 0.1,
+    deadline: None,
+    max_attempts: Some(5),
+};
+
+/// Retrying a `unix` target that does not exist yet or refuses connections.
+const UNIX_RETRY: RetryPolicy = TCP_RETRY;
+
+/// Retrying a `relay` endpoint that refuses connections, names a gpg-agent
+/// that is gone or, for pipes, is busy.
+const ENDPOINT_RETRY: RetryPolicy = TCP_RETRY;
+
+/// What a `gpg` relay knows about the traffic it carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum GpgProtocol {
+    /// Copy bytes without looking at them.
+    Raw,
+    /// Parse Assuan lines and check them on the way.
+    Assuan,
+}
+
+/// What a `pipe` or `gpg-ssh` relay knows about the traffic it carries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SshProtocol {
+    /// Copy bytes without looking at them.
+    Raw,
+    /// Parse SSH agent messages and pass them on one at a time.
+    SshAgent,
+}
+
+/// Which SSH agent requests and keys a `pipe` or `gpg-ssh` relay lets
+/// through. Setting any of these implies `--protocol ssh-agent`.
+#[derive(Clone, Debug, clap::Args)]
+struct PolicyArgs {
+    /// Only forward these operations and refuse everything else locally.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    allow: Vec<ssh_agent::Operation>,
+    /// Only allow listing keys and signing, i.e. `--allow list,sign`.
+    #[arg(long, conflicts_with = "allow")]
+    read_only: bool,
+    /// Only expose keys with one of these SHA256 fingerprints.
+    #[arg(long = "key-fingerprint", value_name = "SHA256:...")]
+    key_fingerprints: Vec<String>,
+    /// Only expose keys of one of these types, e.g. `ssh-ed25519`.
+    #[arg(long = "key-type", value_name = "TYPE")]
+    key_types: Vec<String>,
+    /// Only expose keys whose comment matches one of these `*`/`?` patterns.
+    #[arg(long = "key-comment", value_name = "PATTERN")]
+    key_comments: Vec<String>,
+}
+
+impl PolicyArgs {
+    fn policy(self) -> Policy {
+        let policy = if self.read_only {
+            Policy::allow(policy::READ_ONLY)
+        } else if !self.allow.is_empty() {
+            Policy::allow(&self.allow)
+        } else {
+            Policy::default()
+        };
+
+        policy.with_keys(policy::KeyFilter {
+            fingerprints: self.key_fingerprints,
+            types: self.key_types,
+            comments: self.key_comments,
+        })
+    }
+}
+
+/// Where `pipe` and `gpg-ssh` relays record sign requests.
+#[derive(Clone, Debug, clap::Args)]
+struct AuditArgs {
+    /// Append a JSON line for every sign request to this file. Implies
+    /// `--protocol ssh-agent`. WSL paths work as for `--log-file`.
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+    /// Rotate the audit log before it grows past this size, e.g. `512K`.
+    #[arg(long, value_name = "SIZE", default_value = "10M", value_parser = audit::parse_size)]
+    audit_max_size: u64,
+    /// Number of rotated audit logs to keep.
+    #[arg(long, value_name = "N", default_value_t = audit::DEFAULT_KEEP)]
+    audit_keep: u32,
+    // The WSL process being served, passed on by `listen`.
+    #[arg(long, hide = true)]
+    client_pid: Option<u32>,
+    #[arg(long, hide = true)]
+    client_uid: Option<u32>,
+    #[arg(long, hide = true)]
+    client_command: Option<String>,
+}
+
+impl AuditArgs {
+    fn log(self) -> Option<AuditLog> {
+        Some(AuditLog {
+            path: logging::host_path(&self.audit_log?),
+            max_size: self.audit_max_size,
+            keep: self.audit_keep,
+            client: audit::Client {
+                pid: self.client_pid,
+                uid: self.client_uid,
+                command: self.client_command,
+            },
+        })
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -26,6 +486,8 @@ enum Mode {
 struct Args {
     #[command(subcommand)]
     mode: Mode,
+    #[command(flatten)]
+    log: logging::LogArgs,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -33,95 +495,1075 @@ enum Error {
     #[error("IO error: {0}")]
     IO(#[source] std::io::Error),
 
-    #[error("Failed to parse int: {0}")]
-    ParseInt(#[source] ParseIntError),
+    #[error(
+        "Failed to read {}{}: {source}",
+        path.display(),
+        .dir_source.map(|dir| format!(" (socket directory from {dir})")).unwrap_or_default()
+    )]
+    ReadSocketFile {
+        path: PathBuf,
+        /// Where the socket directory came from, unless the path was given.
+        dir_source: Option<gnupg_dir::Source>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid socket file {}: {source}", path.display())]
+    SocketFile {
+        path: PathBuf,
+        #[source]
+        source: SocketFileError,
+    },
+
+    #[error("Failed to connect to {} ({format} socket file, port {port}): {source}", path.display())]
+    Connect {
+        path: PathBuf,
+        format: socket_file::Format,
+        port: u16,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to launch gpg-agent with `{command}`: {source}")]
+    Launch {
+        command: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("gpg-agent did not start within {timeout:?}: {source}")]
+    LaunchTimeout {
+        timeout: Duration,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("{} kept naming a gpg-agent that is gone, gave up after {attempts} attempts: {source}", path.display())]
+    StaleSocketFile {
+        path: PathBuf,
+        attempts: u32,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("Failed to read preamble {}: {source}", path.display())]
+    Preamble {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to connect to {address}: {source}")]
+    TcpConnect {
+        address: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to connect to {}: {source}", path.display())]
+    UnixConnect {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to open {endpoint}: {source}")]
+    Endpoint {
+        endpoint: Endpoint,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[cfg(not(windows))]
+    #[error("{0} is not supported on this platform")]
+    UnsupportedEndpoint(Endpoint),
 
-    #[error("Invalid number of bytes {0} expected 16 bytes")]
-    InvalidNonce(usize),
+    #[error("Only one side of a relay can be stdio:")]
+    TwoStdio,
 
     #[error("Could not determine home directory")]
     HomeDir,
+
+    #[error("{0}")]
+    Relay(#[source] RelayError),
+
+    #[error("{0}")]
+    Timeout(#[source] relay::Timeout),
+
+    #[error("Mode {0} cannot be used as a multiplexed stream")]
+    NotABridge(&'static str),
+
+    #[error("{0} cannot be opened by a multiplexed stream")]
+    ExecOverStream(Endpoint),
+
+    #[error("{0} cannot be used by a multiplexed stream")]
+    OptionOverStream(&'static str),
+
+    #[cfg(unix)]
+    #[error("{0}")]
+    Config(#[source] config::ConfigError),
+}
+
+impl Error {
+    /// Whether this looks like gpg-agent is not running: its socket file is
+    /// missing or incomplete, nothing listens on the port it names, or the
+    /// listener turned the nonce down.
+    fn agent_not_running(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self {
+            Error::ReadSocketFile { source, .. } => source.kind() == ErrorKind::NotFound,
+            Error::SocketFile { .. } => true,
+            Error::Connect { source, .. } => matches!(
+                source.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
     let args = Args::parse();
+    if let Err(err) = args.log.init() {
+        eprintln!("Error: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let result = run(args.mode).await;
+    if let Err(err) = &result {
+        // Errors always go to stderr as well, where the user running the
+        // command looks for them.
+        if args.log.log_file.is_some() {
+            tracing::error!("{err}");
+        }
+        eprintln!("Error: {err}");
+    }
 
-    match args.mode {
-        Mode::Gpg { socket } => gpg_conn(socket).await,
-        Mode::Pipe { poll, name } => ssh_conn(poll, &name).await,
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Error::Timeout(_)) => ExitCode::from(TIMEOUT_EXIT_CODE),
+        Err(_) => ExitCode::FAILURE,
     }
 }
 
-async fn gpg_conn(socket_name: String) -> Result<(), Error> {
-    let home = home::home_dir().ok_or(Error::HomeDir)?;
-    let socket_file_path = Path::new(home.to_str().ok_or(Error::HomeDir)?)
-        .join("AppData")
-        .join("Local")
-        .join("gnupg")
-        .join(socket_name);
+async fn run(mode: Mode) -> Result<(), Error> {
+    match mode {
+        #[cfg(windows)]
+        Mode::Serve {
+            hvsock_port: Some(port),
+            vm_id: Some(vm_id),
+        } => serve_hvsock(vm_id, port).await,
+        Mode::Serve { .. } => serve(io::stdin(), io::stdout()).await,
+        #[cfg(unix)]
+        Mode::Listen {
+            socket,
+            socket_mode,
+            exe,
+            multiplex,
+            vsock_port,
+            args,
+        } => {
+            let backend = if multiplex || vsock_port.is_some() {
+                let (log_args, target) = logging::split_log_args(args);
+                if let Some(option) = backend::refused_stream_option(&target) {
+                    return Err(Error::OptionOverStream(option));
+                }
 
-    let socket_file = tokio::fs::File::open(socket_file_path)
+                let server = if let Some(port) = vsock_port {
+                    if !log_args.is_empty() {
+                        tracing::warn!(
+                            "logging options for the relay are ignored with --vsock-port, give them to the Windows serve process instead"
+                        );
+                    }
+                    Server::new(vsock::Vsock {
+                        cid: vsock::HOST_CID,
+                        port,
+                    })
+                } else {
+                    let exe = exe.expect("required without --vsock-port");
+                    let args = [vec!["serve".to_owned()], log_args].concat();
+                    Server::new(RelayCommand { exe, args })
+                };
+                Backend::Multiplexed {
+                    server: server.into(),
+                    target,
+                }
+            } else {
+                let exe = exe.expect("required without --vsock-port");
+                Backend::Spawn(RelayCommand { exe, args })
+            };
+            listen::listen(&socket, socket_mode, backend)
+                .await
+                .map_err(Error::IO)
+        }
+        #[cfg(windows)]
+        Mode::PipeServer {
+            name,
+            socket,
+            distribution,
+            exe,
+            wsl,
+            multiplex,
+        } => {
+            let mut wsl_args = Vec::new();
+            if let Some(distribution) = distribution {
+                wsl_args.extend(["--distribution".to_owned(), distribution]);
+            }
+            wsl_args.extend(["--exec".to_owned(), exe]);
+
+            let target = vec![
+                "relay".to_owned(),
+                "stdio:".into(),
+                format!("unix:{socket}"),
+            ];
+            let backend = if multiplex {
+                let args = [wsl_args, vec!["serve".to_owned()]].concat();
+                Backend::Multiplexed {
+                    server: Server::new(RelayCommand { exe: wsl, args }).into(),
+                    target,
+                }
+            } else {
+                let args = [wsl_args, target].concat();
+                Backend::Spawn(RelayCommand { exe: wsl, args })
+            };
+            pipe_server::serve(&name, backend).await.map_err(Error::IO)
+        }
+        #[cfg(unix)]
+        Mode::Daemon { config, check } => {
+            let path = config.or_else(config::default_path).ok_or(Error::HomeDir)?;
+            let config = config::Config::load(&path).map_err(Error::Config)?;
+            if check {
+                return Ok(());
+            }
+            daemon::run(config).await.map_err(Error::IO)
+        }
+        mode => bridge(mode, stdio()).await,
+    }
+}
+
+/// Runs a relay mode between `client` and the endpoint it describes, for as
+/// long as its timeouts allow.
+async fn bridge<C: Stream>(mode: Mode, client: C) -> Result<(), Error> {
+    let timeouts = match &mode {
+        Mode::Gpg { timeouts, .. }
+        | Mode::GpgSsh { timeouts, .. }
+        | Mode::Tcp { timeouts, .. }
+        | Mode::Unix { timeouts, .. }
+        | Mode::Relay { timeouts, .. } => timeouts.timeouts(),
+        #[cfg(windows)]
+        Mode::Pipe { timeouts, .. } => timeouts.timeouts(),
+        _ => relay::Timeouts::default(),
+    };
+    let (client, activity) = relay::Tracked::new(client);
+
+    let result = timeouts
+        .enforce(&activity, bridge_untimed(mode, client, &activity))
         .await
-        .map_err(Error::IO)?;
-    let mut buf = BufReader::new(socket_file);
-    let mut port_buf = String::new();
-    let mut nonce_buf = [0; 16];
+        .map_err(Error::Timeout)?;
+    if result.is_ok() {
+        tracing::info!("session closed");
+    }
+    result
+}
+
+async fn bridge_untimed<C: Stream>(
+    mode: Mode,
+    client: C,
+    activity: &Arc<relay::Activity>,
+) -> Result<(), Error> {
+    match mode {
+        Mode::Gpg {
+            socket,
+            protocol,
+            restricted,
+            allow_command,
+            dirs,
+            retry,
+            timeouts: _,
+        } => {
+            let policy = if restricted {
+                assuan::CommandPolicy::restricted(&allow_command)
+            } else {
+                assuan::CommandPolicy::default()
+            };
+            let retry = retry.policy(STALE_RETRY);
+            gpg_conn(client, socket, dirs, &retry, protocol, policy, activity).await
+        }
+        Mode::GpgSsh {
+            socket,
+            protocol,
+            policy,
+            audit,
+            dirs,
+            retry,
+            timeouts: _,
+        } => {
+            let retry = retry.policy(STALE_RETRY);
+            let agent = AgentOptions {
+                protocol,
+                policy: policy.policy(),
+                audit: audit.log(),
+            };
+            gpg_ssh_conn(client, socket, dirs, &retry, agent, activity).await
+        }
+        #[cfg(windows)]
+        Mode::Pipe {
+            poll,
+            name,
+            protocol,
+            linger,
+            policy,
+            audit,
+            retry,
+            timeouts: _,
+        } => {
+            let retry = retry.policy(PIPE_RETRY);
+            let linger = linger.unwrap_or(PIPE_LINGER);
+            let agent = AgentOptions {
+                protocol,
+                policy: policy.policy(),
+                audit: audit.log(),
+            };
+            ssh_conn(client, poll, &name, &retry, agent, linger, activity).await
+        }
+        Mode::Tcp {
+            address,
+            preamble,
+            connect_timeout,
+            retry,
+            timeouts: _,
+        } => {
+            let retry = retry.policy(TCP_RETRY);
+            tcp_conn(
+                client,
+                &address,
+                preamble,
+                connect_timeout,
+                &retry,
+                activity,
+            )
+            .await
+        }
+        Mode::Unix {
+            path,
+            retry,
+            timeouts: _,
+        } => {
+            let retry = retry.policy(UNIX_RETRY);
+            unix_conn(client, path, &retry, activity).await
+        }
+        Mode::Relay {
+            a,
+            b,
+            retry,
+            timeouts: _,
+        } => {
+            let retry = retry.policy(ENDPOINT_RETRY);
+            relay_conn(client, a, b, &retry, activity).await
+        }
+        Mode::Serve { .. } => Err(Error::NotABridge("serve")),
+        #[cfg(unix)]
+        Mode::Listen { .. } => Err(Error::NotABridge("listen")),
+        #[cfg(windows)]
+        Mode::PipeServer { .. } => Err(Error::NotABridge("pipe-server")),
+        #[cfg(unix)]
+        Mode::Daemon { .. } => Err(Error::NotABridge("daemon")),
+    }
+}
 
-    buf.read_line(&mut port_buf).await.map_err(Error::IO)?;
-    let n = buf.read(&mut nonce_buf).await.map_err(Error::IO)?;
-    if n != 16 {
-        return Err(Error::InvalidNonce(n));
+/// Relays streams multiplexed over `reader` and `writer` until the channel
+/// closes.
+async fn serve<R, W>(reader: R, writer: W) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (_mux, mut incoming) = Mux::start(reader, writer, Side::Server);
+
+    while let Some(stream) = incoming.accept().await {
+        tokio::spawn(serve_stream(stream));
     }
 
-    let port: u16 = port_buf.trim().parse().map_err(Error::ParseInt)?;
+    Ok(())
+}
+
+/// Serves every Hyper-V socket connection from the VM `vm_id`
+/// on vsock `port` as a channel of multiplexed streams, until Ctrl+C.
+#[cfg(windows)]
+async fn serve_hvsock(vm_id: u128, port: u32) -> Result<(), Error> {
+    let mut listener = vsock::HvsockListener::bind(vm_id, port).map_err(Error::IO)?;
+    tracing::info!(
+        service = vsock::format_guid(vsock::service_id(port)),
+        "listening on Hyper-V socket"
+    );
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            stream = listener.accept() => stream.map_err(Error::IO)?,
+            result = &mut shutdown => return result.map_err(Error::IO),
+        };
+        tracing::debug!("accepted Hyper-V socket connection");
+        let (reader, writer) = io::split(stream);
+        tokio::spawn(serve(reader, writer));
+    }
+}
+
+async fn serve_stream(stream: IncomingStream) {
+    let IncomingStream { target, io, abort } = stream;
+    let span = tracing::info_span!("stream", target = %target.join(" "));
+    let argv = std::iter::once("wsl2-bridge-rs").chain(target.iter().map(String::as_str));
 
-    let mut stream = TcpStream::connect(format!("localhost:{}", port))
+    let result = match Args::try_parse_from(argv) {
+        Ok(args) => match check_stream(&target, &args.mode) {
+            Ok(()) => bridge(args.mode, io)
+                .instrument(span.clone())
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        },
+        Err(err) => Err(err.to_string()),
+    };
+
+    if let Err(message) = result {
+        span.in_scope(|| tracing::warn!("{message}"));
+        abort.send(message).await;
+    }
+}
+
+/// Checks that the other end of a multiplexed channel may run `mode`, parsed
+/// from the stream's arguments `args`.
+///
+/// Whoever can open streams only gets to reach the services a bridge
+/// connects to, never to start programs or open files of their choosing.
+fn check_stream(args: &[String], mode: &Mode) -> Result<(), Error> {
+    if let Some(option) = backend::refused_stream_option(args) {
+        return Err(Error::OptionOverStream(option));
+    }
+
+    match mode {
+        Mode::Gpg { .. } | Mode::GpgSsh { .. } | Mode::Tcp { .. } | Mode::Unix { .. } => Ok(()),
+        #[cfg(windows)]
+        Mode::Pipe { .. } => Ok(()),
+        Mode::Relay { a, b, .. } => match [a, b]
+            .into_iter()
+            .find(|endpoint| matches!(endpoint, Endpoint::Exec(_)))
+        {
+            Some(exec) => Err(Error::ExecOverStream(exec.clone())),
+            None => Ok(()),
+        },
+        Mode::Serve { .. } => Err(Error::NotABridge("serve")),
+        #[cfg(unix)]
+        Mode::Listen { .. } => Err(Error::NotABridge("listen")),
+        #[cfg(windows)]
+        Mode::PipeServer { .. } => Err(Error::NotABridge("pipe-server")),
+        #[cfg(unix)]
+        Mode::Daemon { .. } => Err(Error::NotABridge("daemon")),
+    }
+}
+
+#[cfg(unix)]
+fn parse_octal(value: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(value, 8)
+}
+
+/// Our own stdin/stdout as a single relay endpoint.
+fn stdio() -> Join<Stdin, Stdout> {
+    io::join(io::stdin(), io::stdout())
+}
+
+/// Finds the socket file `socket_name` and connects to the port it names,
+/// launching gpg-agent first if that is enabled and needed.
+///
+/// With `greeting`, a connection only counts once the agent has sent its
+/// Assuan greeting, so a stale nonce is noticed here rather than by the
+/// client.
+async fn connect_socket_file(
+    socket_name: String,
+    dirs: GnupgDirArgs,
+    retry: &RetryPolicy,
+    greeting: bool,
+) -> Result<TcpStream, Error> {
+    let locator = gnupg_dir::Locator {
+        homedir: dirs.homedir.as_deref(),
+        gpgconf: Some(&dirs.gpgconf),
+    };
+    let socket_dir = locator
+        .resolve(|name| std::env::var_os(name))
         .await
-        .map_err(Error::IO)?;
+        .ok_or(Error::HomeDir)?;
+    let path = socket_dir.path.join(socket_name);
+    let connect = || try_connect_socket_file(&path, Some(socket_dir.source), greeting);
+
+    if !dirs.launch {
+        return connect_retrying_stale(&path, retry, connect).await;
+    }
+    match connect().await {
+        Err(err) if err.agent_not_running() => {}
+        result => return result,
+    }
+
+    launch::run(&launch::split_command(&dirs.launch_command))
+        .await
+        .map_err(|source| Error::Launch {
+            command: dirs.launch_command.clone(),
+            source,
+        })?;
+    RetryPolicy::constant(launch::LAUNCH_POLL, dirs.launch_timeout)
+        .retry(connect, Error::agent_not_running)
+        .await
+        .map_err(|err| {
+            if err.agent_not_running() {
+                Error::LaunchTimeout {
+                    timeout: dirs.launch_timeout,
+                    source: Box::new(err),
+                }
+            } else {
+                err
+            }
+        })
+}
+
+/// Connects, reading the socket file again for as long as `retry` allows
+/// while it names an agent that is gone.
+async fn connect_retrying_stale<F>(
+    path: &Path,
+    retry: &RetryPolicy,
+    mut connect: impl FnMut() -> F,
+) -> Result<TcpStream, Error>
+where
+    F: Future<Output = Result<TcpStream, Error>>,
+{
+    let mut attempts = 0;
+    let result = retry
+        .retry(
+            move || {
+                attempts += 1;
+                let number = attempts;
+                let connecting = connect();
+                async move { connecting.await.map_err(|err| Attempt { number, err }) }
+            },
+            // A socket file missing from the start belongs to an agent that
+            // never ran, which is not worth waiting for.
+            |Attempt { number, err }| {
+                err.agent_not_running()
+                    && !(*number == 1 && matches!(err, Error::ReadSocketFile { .. }))
+            },
+        )
+        .await;
+
+    result.map_err(|Attempt { number, err }| {
+        if err.agent_not_running() && number > 1 {
+            Error::StaleSocketFile {
+                path: path.to_owned(),
+                attempts: number,
+                source: Box::new(err),
+            }
+        } else {
+            err
+        }
+    })
+}
+
+/// An error from the connection attempt `number`, counting from 1.
+struct Attempt {
+    number: u32,
+    err: Error,
+}
+
+impl std::fmt::Display for Attempt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.err.fmt(f)
+    }
+}
 
-    stream.write_all(&nonce_buf).await.map_err(Error::IO)?;
+async fn try_connect_socket_file(
+    path: &Path,
+    dir_source: Option<gnupg_dir::Source>,
+    greeting: bool,
+) -> Result<TcpStream, Error> {
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|source| Error::ReadSocketFile {
+            path: path.to_owned(),
+            dir_source,
+            source,
+        })?;
+    let socket_file = SocketFile::parse(&contents).map_err(|source| Error::SocketFile {
+        path: path.to_owned(),
+        source,
+    })?;
+
+    let connect = async {
+        let stream = socket_file.connect().await?;
+        if greeting {
+            socket_file::await_greeting(&stream).await?;
+        }
+        Ok(stream)
+    };
+    let stream = connect.await.map_err(|source| Error::Connect {
+        path: path.to_owned(),
+        format: socket_file.format(),
+        port: socket_file.port(),
+        source,
+    })?;
+
+    tracing::info!(
+        path = %path.display(),
+        format = %socket_file.format(),
+        port = socket_file.port(),
+        "connected to gpg-agent"
+    );
+    Ok(stream)
+}
 
-    let (mut stream_in, mut stream_out) = stream.split();
-    let mut stdin = io::stdin();
-    let mut stdout = io::stdout();
+async fn gpg_conn<C>(
+    client: C,
+    socket_name: String,
+    dirs: GnupgDirArgs,
+    retry: &RetryPolicy,
+    protocol: GpgProtocol,
+    policy: assuan::CommandPolicy,
+    activity: &relay::Activity,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let stream = connect_socket_file(socket_name, dirs, retry, true).await?;
+    activity.connected();
 
-    let mut reader = async move || io::copy(&mut stdin, &mut stream_out).await;
-    let mut writer = async move || io::copy(&mut stream_in, &mut stdout).await;
+    if protocol == GpgProtocol::Assuan || policy.is_restricted() {
+        return assuan::relay(client, stream, &policy)
+            .await
+            .map_err(Error::IO);
+    }
 
-    let (h1, h2) = tokio::join!(reader(), writer());
-    h1.map_err(Error::IO)?;
-    h2.map_err(Error::IO)?;
+    Relay::new(client, stream)
+        .run()
+        .await
+        .map_err(Error::Relay)?;
 
     Ok(())
 }
 
-async fn connect_pipe(poll: bool, pipe_name: &str) -> io::Result<NamedPipeClient> {
-    loop {
-        match ClientOptions::new().open(pipe_name) {
-            Ok(client) => break Ok(client),
-            Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {}
-            Err(err) if err.kind() == io::ErrorKind::NotFound && poll => {}
+async fn tcp_conn<C>(
+    client: C,
+    address: &str,
+    preamble: Option<PathBuf>,
+    connect_timeout: Option<Duration>,
+    retry: &RetryPolicy,
+    activity: &relay::Activity,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    // Read before connecting, so a missing file does not leave the service
+    // with a half-open connection.
+    let preamble = match preamble {
+        Some(path) => std::fs::read(logging::host_path(&path))
+            .map_err(|source| Error::Preamble { path, source })?,
+        None => Vec::new(),
+    };
+
+    let mut stream = tcp::connect(address, connect_timeout, retry)
+        .await
+        .map_err(|source| Error::TcpConnect {
+            address: address.to_owned(),
+            source,
+        })?;
+    stream.write_all(&preamble).await.map_err(Error::IO)?;
+    activity.connected();
+
+    Relay::new(client, stream)
+        .run()
+        .await
+        .map_err(Error::Relay)?;
+
+    Ok(())
+}
+
+/// Relays to the Unix socket at `path`.
+async fn unix_conn<C>(
+    client: C,
+    path: PathBuf,
+    retry: &RetryPolicy,
+    activity: &relay::Activity,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let stream = unix_socket::connect(&path, retry)
+        .await
+        .map_err(|source| Error::UnixConnect { path, source })?;
+    activity.connected();
+
+    Relay::new(client, stream)
+        .run()
+        .await
+        .map_err(Error::Relay)?;
+
+    Ok(())
+}
+
+/// Relays between endpoints `a` and `b`, either of which may be the
+/// relay's own `client`. Traffic is recorded in `activity`, which `client`
+/// already does if it is used.
+async fn relay_conn<C: Stream>(
+    client: C,
+    a: Endpoint,
+    b: Endpoint,
+    retry: &RetryPolicy,
+    activity: &Arc<relay::Activity>,
+) -> Result<(), Error> {
+    if a == Endpoint::Stdio && b == Endpoint::Stdio {
+        return Err(Error::TwoStdio);
+    }
+    let uses_client = a == Endpoint::Stdio || b == Endpoint::Stdio;
+
+    let mut client = Some(client);
+    let a = open_endpoint(&a, &mut client, retry).await?;
+    let mut b = open_endpoint(&b, &mut client, retry).await?;
+    if !uses_client {
+        b = Box::new(relay::Tracked::sharing(b, activity));
+    }
+    activity.connected();
+    Relay::new(a, b).run().await.map_err(Error::Relay)?;
+
+    Ok(())
+}
+
+/// Connects `endpoint`, which is `client` for `stdio:`.
+async fn open_endpoint<'c, C: Stream + 'c>(
+    endpoint: &Endpoint,
+    client: &mut Option<C>,
+    retry: &RetryPolicy,
+) -> Result<Box<dyn Stream + 'c>, Error> {
+    let failed = |source| Error::Endpoint {
+        endpoint: endpoint.clone(),
+        source,
+    };
+
+    let stream: Box<dyn Stream> = match endpoint {
+        Endpoint::Stdio => {
+            let client = client.take().ok_or(Error::TwoStdio)?;
+            return Ok(Box::new(client));
+        }
+        #[cfg(windows)]
+        Endpoint::NamedPipe(name) => {
+            Box::new(connect_pipe(false, name, retry).await.map_err(failed)?)
+        }
+        Endpoint::Tcp(address) => {
+            Box::new(tcp::connect(address, None, retry).await.map_err(failed)?)
+        }
+        Endpoint::Unix(path) => Box::new(unix_socket::connect(path, retry).await.map_err(failed)?),
+        Endpoint::Assuan(path) => {
+            let path = logging::host_path(path);
+            let connect = || try_connect_socket_file(&path, None, false);
+            Box::new(connect_retrying_stale(&path, retry, connect).await?)
+        }
+        Endpoint::Exec(command) => {
+            let (program, args) = command
+                .split_first()
+                .expect("parsed commands are not empty");
+            Box::new(
+                endpoint::spawn(tokio::process::Command::new(program).args(args))
+                    .map_err(failed)?,
+            )
+        }
+        #[cfg(not(windows))]
+        Endpoint::NamedPipe(_) => return Err(Error::UnsupportedEndpoint(endpoint.clone())),
+    };
+
+    tracing::info!(%endpoint, "opened endpoint");
+    Ok(stream)
+}
+
+/// How `pipe` and `gpg-ssh` relays handle SSH agent messages.
+struct AgentOptions {
+    protocol: SshProtocol,
+    policy: Policy,
+    audit: Option<AuditLog>,
+}
+
+impl AgentOptions {
+    /// Whether messages have to be parsed rather than relayed as raw bytes.
+    fn parses_messages(&self) -> bool {
+        self.protocol == SshProtocol::SshAgent
+            || self.policy.is_restricted()
+            || self.audit.is_some()
+    }
+
+    /// Puts the policy and audit log in front of `agent`.
+    fn wrap<A: ssh_agent::Agent + Send>(self, agent: A) -> Audited<policy::Guarded<A>> {
+        Audited::new(policy::Guarded::new(agent, self.policy), self.audit)
+    }
+}
+
+/// Relays SSH agent traffic to gpg-agent, which serves it on a socket file
+/// of its own next to the Assuan ones.
+async fn gpg_ssh_conn<C>(
+    client: C,
+    socket_name: String,
+    dirs: GnupgDirArgs,
+    retry: &RetryPolicy,
+    agent: AgentOptions,
+    activity: &relay::Activity,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let stream = connect_socket_file(socket_name, dirs, retry, false).await?;
+    activity.connected();
+
+    if agent.parses_messages() {
+        let mut agent = agent.wrap(ssh_agent::Upstream::new(stream));
+        return ssh_agent::serve(client, &mut agent)
+            .await
+            .map_err(Error::IO);
+    }
+
+    Relay::new(client, stream)
+        .run()
+        .await
+        .map_err(Error::Relay)?;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+async fn connect_pipe(
+    poll: bool,
+    pipe_name: &str,
+    retry: &RetryPolicy,
+) -> io::Result<NamedPipeClient> {
+    let pipe = retry
+        .retry(
+            || async { ClientOptions::new().open(pipe_name) },
+            |err| {
+                err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32)
+                    || (poll && err.kind() == io::ErrorKind::NotFound)
+            },
+        )
+        .await?;
+
+    tracing::info!(pipe = %pipe_name, "connected to pipe");
+    Ok(pipe)
+}
+
+/// Connects every pipe in `pipe_names` that exists.
+///
+/// A single pipe has to exist (or appear, with `poll`); of several pipes,
+/// missing ones are skipped as long as at least one can be connected.
+#[cfg(windows)]
+async fn connect_pipes(
+    poll: bool,
+    pipe_names: &[String],
+    retry: &RetryPolicy,
+) -> io::Result<Vec<NamedPipeClient>> {
+    if let [pipe_name] = pipe_names {
+        return Ok(vec![connect_pipe(poll, pipe_name, retry).await?]);
+    }
+
+    retry
+        .retry(
+            || connect_existing_pipes(pipe_names, retry),
+            |err| poll && err.kind() == io::ErrorKind::NotFound,
+        )
+        .await
+}
+
+/// Connects the pipes in `pipe_names` that exist, failing if none do.
+#[cfg(windows)]
+async fn connect_existing_pipes(
+    pipe_names: &[String],
+    retry: &RetryPolicy,
+) -> io::Result<Vec<NamedPipeClient>> {
+    let mut pipes = Vec::new();
+    let mut missing = Vec::new();
+    for pipe_name in pipe_names {
+        match connect_pipe(false, pipe_name, retry).await {
+            Ok(pipe) => pipes.push(pipe),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => missing.push(pipe_name),
             Err(err) => return Err(err),
         }
+    }
 
-        tokio::time::sleep(Duration::from_millis(50)).await;
+    if pipes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("none of the pipes {} exist", pipe_names.join(", ")),
+        ));
+    }
+    for pipe_name in missing {
+        tracing::warn!(pipe = %pipe_name, "skipping SSH agent: pipe not found");
     }
+    Ok(pipes)
 }
 
-async fn ssh_conn(poll: bool, pipe_name: &str) -> Result<(), Error> {
-    let client = connect_pipe(poll, pipe_name).await.map_err(Error::IO)?;
-    let client = BufStream::new(client);
-    let (mut np_reader, mut np_writer) = io::split(client);
-    let mut stdout = io::stdout();
-    let mut stdin = io::stdin();
+#[cfg(windows)]
+async fn ssh_conn<C>(
+    client: C,
+    poll: bool,
+    pipe_names: &[String],
+    retry: &RetryPolicy,
+    agent: AgentOptions,
+    linger: Duration,
+    activity: &relay::Activity,
+) -> Result<(), Error>
+where
+    C: AsyncRead + AsyncWrite,
+{
+    let mut pipes = connect_pipes(poll, pipe_names, retry)
+        .await
+        .map_err(Error::IO)?;
+    activity.connected();
 
-    let mut stdin_to_pipe = async || io::copy(&mut stdin, &mut np_writer).await;
-    let mut pipe_to_stdout = async || io::copy(&mut np_reader, &mut stdout).await;
+    if pipes.len() > 1 {
+        let agents = pipes.into_iter().map(ssh_agent::Upstream::new).collect();
+        let mut agent = agent.wrap(merge::Merged::new(agents));
+        return ssh_agent::serve(client, &mut agent)
+            .await
+            .map_err(Error::IO);
+    }
 
-    let (h1, h2) = tokio::join!(stdin_to_pipe(), pipe_to_stdout());
-    h1.map_err(Error::IO)?;
-    h2.map_err(Error::IO)?;
+    let pipe = pipes.pop().expect("at least one pipe is connected");
+    if agent.parses_messages() {
+        let mut agent = agent.wrap(ssh_agent::Upstream::new(pipe));
+        return ssh_agent::serve(client, &mut agent)
+            .await
+            .map_err(Error::IO);
+    }
+
+    Relay::new(client, pipe)
+        .half_close_b(HalfClose::Linger(linger))
+        .run()
+        .await
+        .map_err(Error::Relay)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_mode(target: &str) -> Result<(), String> {
+        let target: Vec<String> = target.split(' ').map(String::from).collect();
+        let argv = std::iter::once("wsl2-bridge-rs").chain(target.iter().map(String::as_str));
+        let args = Args::try_parse_from(argv).unwrap();
+        check_stream(&target, &args.mode).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn streams_only_run_bridges() {
+        assert_eq!(stream_mode("tcp --address localhost:22"), Ok(()));
+        assert_eq!(stream_mode("unix --path /tmp/agent.sock"), Ok(()));
+        assert_eq!(stream_mode("gpg-ssh"), Ok(()));
+        assert_eq!(stream_mode("relay stdio: unix:/tmp/agent.sock"), Ok(()));
+
+        assert_eq!(
+            stream_mode("relay stdio: exec:calc.exe"),
+            Err("exec:calc.exe cannot be opened by a multiplexed stream".into())
+        );
+        assert_eq!(
+            stream_mode("relay exec:sh stdio:"),
+            Err("exec:sh cannot be opened by a multiplexed stream".into())
+        );
+        assert_eq!(
+            stream_mode("serve"),
+            Err("Mode serve cannot be used as a multiplexed stream".into())
+        );
+    }
+
+    #[test]
+    fn launch_timeouts_are_durations() {
+        let parse = |timeout: &str| {
+            Args::try_parse_from([
+                "wsl2-bridge-rs",
+                "gpg",
+                "--socket",
+                "S.gpg-agent",
+                "--launch",
+                "--launch-timeout",
+                timeout,
+            ])
+        };
+        let Mode::Gpg { dirs, .. } = parse("30s").unwrap().mode else {
+            panic!("not a gpg relay");
+        };
+        assert_eq!(dirs.launch_timeout, Duration::from_secs(30));
+
+        assert!(parse("18446744073709551615").is_err());
+        assert!(parse("18446744073709551615s").is_err());
+    }
+
+    #[test]
+    fn streams_refuse_options_that_run_programs_or_open_files() {
+        assert_eq!(
+            stream_mode("gpg --socket S.gpg-agent --homedir /tmp/gnupg --retry-deadline 5s"),
+            Ok(())
+        );
+
+        for (target, option) in [
+            ("gpg --socket S.gpg-agent --launch", "--launch"),
+            (
+                "gpg-ssh --launch-command=calc.exe --launch",
+                "--launch-command",
+            ),
+            ("gpg --socket S.gpg-agent --gpgconf calc.exe", "--gpgconf"),
+            ("tcp --address evil:80 --preamble=C:/secret", "--preamble"),
+            ("gpg-ssh --audit-log /mnt/c/Windows/x.dll", "--audit-log"),
+            ("tcp --address localhost:22 --log-file /tmp/x", "--log-file"),
+        ] {
+            assert_eq!(
+                stream_mode(target),
+                Err(format!("{option} cannot be used by a multiplexed stream")),
+                "{target}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_without_stdio_count_their_own_traffic() {
+        use tokio::net::TcpListener;
+
+        // One end talks for a while and then goes quiet, the other listens.
+        let talker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = format!(
+            "relay tcp://{} tcp://{} --idle-timeout 100ms",
+            talker.local_addr().unwrap(),
+            listener.local_addr().unwrap()
+        );
+        tokio::spawn(async move {
+            let (mut stream, _) = talker.accept().await.unwrap();
+            for _ in 0..6 {
+                stream.write_all(b"x").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            std::future::pending::<()>().await;
+        });
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let argv = std::iter::once("wsl2-bridge-rs").chain(target.split(' '));
+        let args = Args::try_parse_from(argv).unwrap();
+        let started = tokio::time::Instant::now();
+        let (client, _unused) = tokio::io::duplex(64);
+        let result = bridge(args.mode, client).await;
+
+        assert!(matches!(
+            result,
+            Err(Error::Timeout(relay::Timeout::Idle(_)))
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+}
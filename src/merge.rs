@@ -0,0 +1,236 @@
+//! A single SSH agent view over several upstream agents.
+//!
+//! Key listings are gathered from every agent and de-duplicated, and each
+//! request about a particular key is routed to the agent that listed it.
+//! Session binds go to every agent, as any of them may be asked to sign for
+//! the session.
+
+use crate::ssh_agent::{self, Agent, Identity, Request, Response};
+use std::{collections::HashMap, io};
+
+/// Several agents presented as one.
+///
+/// An agent whose connection fails is dropped and the others carry on; only
+/// once every agent is gone do requests fail.
+pub struct Merged<A> {
+    agents: Vec<Option<A>>,
+    /// Which agent listed each key blob first.
+    owners: HashMap<Vec<u8>, usize>,
+}
+
+impl<A: Agent + Send> Merged<A> {
+    pub fn new(agents: Vec<A>) -> Self {
+        Self {
+            agents: agents.into_iter().map(Some).collect(),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Sends `request` to agent `index`, dropping the agent if that fails.
+    async fn call_one(&mut self, index: usize, request: Request) -> Option<Response> {
+        let agent = self.agents[index].as_mut()?;
+        match agent.call(request).await {
+            Ok(response) => Some(response),
+            Err(err) => {
+                tracing::warn!(agent = index, %err, "SSH agent failed and is no longer used");
+                self.agents[index] = None;
+                None
+            }
+        }
+    }
+
+    fn check_connected(&self) -> io::Result<()> {
+        if self.agents.iter().all(Option::is_none) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "no SSH agent is reachable",
+            ));
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self) -> io::Result<Response> {
+        let mut identities: Vec<Identity> = Vec::new();
+        self.owners.clear();
+
+        for index in 0..self.agents.len() {
+            let Some(Response::IdentitiesAnswer(listed)) =
+                self.call_one(index, Request::RequestIdentities).await
+            else {
+                continue;
+            };
+            for identity in listed {
+                if !self.owners.contains_key(&identity.key_blob) {
+                    self.owners.insert(identity.key_blob.clone(), index);
+                    identities.push(identity);
+                }
+            }
+        }
+
+        self.check_connected()?;
+        Ok(Response::IdentitiesAnswer(identities))
+    }
+
+    /// The agent holding `key_blob`, listing keys again if it is not known.
+    async fn owner(&mut self, key_blob: &[u8]) -> io::Result<Option<usize>> {
+        if !self.owners.contains_key(key_blob) {
+            self.list().await?;
+        }
+        Ok(self.owners.get(key_blob).copied())
+    }
+
+    /// Sends `request` to every agent, succeeding only if they all do.
+    async fn broadcast(&mut self, request: Request) -> io::Result<Response> {
+        let mut all_succeeded = true;
+        for index in 0..self.agents.len() {
+            if self.agents[index].is_some() {
+                let response = self.call_one(index, request.clone()).await;
+                all_succeeded &= matches!(response, Some(Response::Success { .. }));
+            }
+        }
+
+        self.check_connected()?;
+        Ok(if all_succeeded {
+            Response::Success { contents: vec![] }
+        } else {
+            Response::Failure
+        })
+    }
+}
+
+impl<A: Agent + Send> Agent for Merged<A> {
+    async fn call(&mut self, request: Request) -> io::Result<Response> {
+        match &request {
+            Request::RequestIdentities => self.list().await,
+            Request::SignRequest { key_blob, .. } | Request::RemoveIdentity { key_blob } => {
+                let Some(index) = self.owner(key_blob).await? else {
+                    return Ok(Response::Failure);
+                };
+                Ok(self
+                    .call_one(index, request)
+                    .await
+                    .unwrap_or(Response::Failure))
+            }
+            Request::RemoveAllIdentities | Request::Lock { .. } | Request::Unlock { .. } => {
+                self.broadcast(request).await
+            }
+            Request::Extension { name, .. } if name == ssh_agent::SESSION_BIND => {
+                self.broadcast(request).await
+            }
+            // Anything else goes to the first agent that is still reachable,
+            // so new keys end up in the primary agent.
+            _ => {
+                self.check_connected()?;
+                let index = self
+                    .agents
+                    .iter()
+                    .position(Option::is_some)
+                    .expect("checked above");
+                Ok(self
+                    .call_one(index, request)
+                    .await
+                    .unwrap_or(Response::Failure))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::testing::{FakeAgent, identity, key, sign};
+
+    #[tokio::test]
+    async fn merges_and_deduplicates_identities() {
+        let mut merged = Merged::new(vec![
+            FakeAgent::new(1, vec![identity(1, "windows"), identity(2, "shared")]),
+            FakeAgent::new(2, vec![identity(2, "shared too"), identity(3, "1password")]),
+        ]);
+
+        assert_eq!(
+            merged.call(Request::RequestIdentities).await.unwrap(),
+            Response::IdentitiesAnswer(vec![
+                identity(1, "windows"),
+                identity(2, "shared"),
+                identity(3, "1password"),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_signatures_to_the_owner() {
+        let mut merged = Merged::new(vec![
+            FakeAgent::new(1, vec![identity(1, "windows")]),
+            FakeAgent::new(2, vec![identity(3, "1password")]),
+        ]);
+
+        // No listing has happened yet, so the owner is looked up on demand.
+        assert_eq!(
+            merged.call(sign(&key(3))).await.unwrap(),
+            Response::SignResponse { signature: vec![2] }
+        );
+        assert_eq!(
+            merged.call(sign(&key(1))).await.unwrap(),
+            Response::SignResponse { signature: vec![1] }
+        );
+        assert_eq!(merged.call(sign(&key(9))).await.unwrap(), Response::Failure);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_and_survives_a_failed_agent() {
+        let mut merged = Merged::new(vec![
+            FakeAgent::new(1, vec![identity(1, "windows")]),
+            FakeAgent::new(2, vec![identity(3, "1password")]),
+        ]);
+
+        let lock = Request::Lock {
+            passphrase: b"x".to_vec(),
+        };
+        assert_eq!(
+            merged.call(lock.clone()).await.unwrap(),
+            Response::Success { contents: vec![] }
+        );
+
+        merged.agents[0].as_mut().unwrap().broken = true;
+        assert_eq!(
+            merged.call(Request::RequestIdentities).await.unwrap(),
+            Response::IdentitiesAnswer(vec![identity(3, "1password")])
+        );
+        assert_eq!(
+            merged.call(sign(&key(3))).await.unwrap(),
+            Response::SignResponse { signature: vec![2] }
+        );
+
+        merged.agents[1].as_mut().unwrap().broken = true;
+        let err = merged.call(lock).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn session_binds_reach_every_agent() {
+        let mut merged = Merged::new(vec![
+            FakeAgent::new(1, vec![identity(1, "windows")]),
+            FakeAgent::new(2, vec![identity(3, "1password")]),
+        ]);
+
+        let bind = ssh_agent::SessionBind {
+            host_key: b"host key".to_vec(),
+            session_id: b"session".to_vec(),
+            signature: b"signature".to_vec(),
+            forwarding: false,
+        }
+        .encode();
+        let request = Request::Extension {
+            name: ssh_agent::SESSION_BIND.into(),
+            contents: bind,
+        };
+        assert_eq!(
+            merged.call(request.clone()).await.unwrap(),
+            Response::Success { contents: vec![] }
+        );
+
+        for agent in &merged.agents {
+            assert_eq!(agent.as_ref().unwrap().requests, vec![request.clone()]);
+        }
+    }
+}
@@ -0,0 +1,980 @@
+//! Stream multiplexing over a single byte channel.
+//!
+//! In `serve` mode one long-lived Windows process carries every relayed
+//! connection over its stdin/stdout instead of paying a process start through
+//! WSL interop per connection. Each connection is a stream with a `u32` id,
+//! and every frame on the wire is
+//!
+//! ```text
+//! +------+-----------+----------+---------------+
+//! | kind | stream id | length   | payload       |
+//! | u8   | u32 (BE)  | u32 (BE) | length bytes  |
+//! +------+-----------+----------+---------------+
+//! ```
+//!
+//! - `OPEN` starts a stream. The payload is the bridge arguments (e.g.
+//!   `pipe --name //./pipe/openssh-ssh-agent`), separated by NUL bytes.
+//! - `DATA` carries bytes for a stream.
+//! - `CLOSE` means the sender will not send any more data on the stream.
+//! - `ERROR` aborts a stream; the payload is a UTF-8 message, which reading
+//!   the stream fails with.
+//! - `WINDOW` lets the other end send more data on a stream; the payload is
+//!   the number of bytes as a `u32` (BE).
+//!
+//! Each end may have at most [`STREAM_WINDOW`] bytes of a stream's data in
+//! flight and sends `WINDOW` once the receiving end has passed data on. A
+//! stream whose reader stalls thus stops only itself, never the frame reader
+//! the other streams share. A stream that sends more than its window is
+//! aborted.
+
+use std::{
+    collections::HashMap,
+    io,
+    pin::Pin,
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::{Semaphore, mpsc},
+};
+
+/// Largest payload accepted in a single frame.
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+/// Bytes of a stream's data that may be in flight in each direction.
+pub const STREAM_WINDOW: usize = 256 * 1024;
+
+const HEADER_LEN: usize = 9;
+const STREAM_BUFFER: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 16 * 1024;
+
+const KIND_OPEN: u8 = 1;
+const KIND_DATA: u8 = 2;
+const KIND_CLOSE: u8 = 3;
+const KIND_ERROR: u8 = 4;
+const KIND_WINDOW: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Frame {
+    Open { id: u32, target: Vec<String> },
+    Data { id: u32, payload: Vec<u8> },
+    Close { id: u32 },
+    Error { id: u32, message: String },
+    Window { id: u32, increment: u32 },
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    #[error("Unknown frame kind {0}")]
+    UnknownKind(u8),
+
+    #[error("Frame payload of {0} bytes exceeds the {MAX_PAYLOAD} byte limit")]
+    TooLarge(usize),
+
+    #[error("Frame payload is not valid UTF-8")]
+    InvalidUtf8,
+
+    #[error("Window update of {0} bytes, expected 4")]
+    InvalidWindow(usize),
+}
+
+impl From<FrameError> for io::Error {
+    fn from(err: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl Frame {
+    pub fn id(&self) -> u32 {
+        match self {
+            Frame::Open { id, .. }
+            | Frame::Data { id, .. }
+            | Frame::Close { id }
+            | Frame::Error { id, .. }
+            | Frame::Window { id, .. } => *id,
+        }
+    }
+
+    /// Appends the wire encoding of this frame to `out`, unless its payload
+    /// is too large for the other end to accept.
+    pub fn encode(&self, out: &mut Vec<u8>) -> Result<(), FrameError> {
+        let (kind, payload) = match self {
+            Frame::Open { target, .. } => (KIND_OPEN, target.join("\0").into_bytes()),
+            Frame::Data { payload, .. } => (KIND_DATA, payload.clone()),
+            Frame::Close { .. } => (KIND_CLOSE, Vec::new()),
+            Frame::Error { message, .. } => (KIND_ERROR, message.clone().into_bytes()),
+            Frame::Window { increment, .. } => (KIND_WINDOW, increment.to_be_bytes().to_vec()),
+        };
+        if payload.len() > MAX_PAYLOAD {
+            return Err(FrameError::TooLarge(payload.len()));
+        }
+
+        out.push(kind);
+        out.extend_from_slice(&self.id().to_be_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(&payload);
+        Ok(())
+    }
+
+    fn from_parts(kind: u8, id: u32, payload: Vec<u8>) -> Result<Frame, FrameError> {
+        let text =
+            |payload: Vec<u8>| String::from_utf8(payload).map_err(|_| FrameError::InvalidUtf8);
+
+        Ok(match kind {
+            KIND_OPEN => Frame::Open {
+                id,
+                target: text(payload)?
+                    .split('\0')
+                    .filter(|arg| !arg.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            },
+            KIND_DATA => Frame::Data { id, payload },
+            KIND_CLOSE => Frame::Close { id },
+            KIND_ERROR => Frame::Error {
+                id,
+                message: text(payload)?,
+            },
+            KIND_WINDOW => Frame::Window {
+                id,
+                increment: u32::from_be_bytes(
+                    payload[..]
+                        .try_into()
+                        .map_err(|_| FrameError::InvalidWindow(payload.len()))?,
+                ),
+            },
+            kind => return Err(FrameError::UnknownKind(kind)),
+        })
+    }
+}
+
+fn parse_header(header: &[u8; HEADER_LEN]) -> Result<(u8, u32, usize), FrameError> {
+    let kind = header[0];
+    let id = u32::from_be_bytes(header[1..5].try_into().expect("id length"));
+    let len = u32::from_be_bytes(header[5..9].try_into().expect("length length")) as usize;
+
+    if !(KIND_OPEN..=KIND_WINDOW).contains(&kind) {
+        return Err(FrameError::UnknownKind(kind));
+    }
+    if len > MAX_PAYLOAD {
+        return Err(FrameError::TooLarge(len));
+    }
+
+    Ok((kind, id, len))
+}
+
+/// Reads the next frame, or `None` on a clean EOF between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0; HEADER_LEN];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+
+    let (kind, id, len) = parse_header(&header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(Frame::from_parts(kind, id, payload)?))
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let mut buf = Vec::new();
+    frame.encode(&mut buf)?;
+    writer.write_all(&buf).await
+}
+
+/// `message` cut down to fit in an `ERROR` frame.
+fn truncate_message(mut message: String) -> String {
+    if message.len() > MAX_PAYLOAD {
+        let mut end = MAX_PAYLOAD;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
+
+/// Which end of the channel we are. Clients open streams with odd ids and
+/// servers with even ids, so both ends can open streams without colliding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+impl Side {
+    /// Whether `id` is one this side opens streams with.
+    fn opens(self, id: u32) -> bool {
+        (id % 2 == 1) == (self == Side::Client)
+    }
+}
+
+/// The frame reader's view of one stream.
+struct StreamState {
+    /// Data received for the stream, until the other end closes its side.
+    inbound: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Bytes the other end may still send before it is granted more.
+    receive_window: Arc<AtomicUsize>,
+    /// Bytes we may still send; `WINDOW` frames add to it.
+    send_window: Arc<Semaphore>,
+    /// Why the stream was aborted.
+    error: Arc<OnceLock<String>>,
+}
+
+type Streams = Arc<Mutex<HashMap<u32, StreamState>>>;
+
+/// Handle to a running multiplexing session.
+#[derive(Clone)]
+pub struct Mux {
+    side: Side,
+    outgoing: mpsc::Sender<Frame>,
+    streams: Streams,
+    next_id: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
+}
+
+/// One end of a multiplexed stream.
+///
+/// Reading it fails rather than reaching EOF once the stream is aborted.
+#[derive(Debug)]
+pub struct MuxStream {
+    io: DuplexStream,
+    error: Arc<OnceLock<String>>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.io).poll_read(cx, buf))?;
+        if buf.filled().len() == filled
+            && buf.remaining() > 0
+            && let Some(message) = self.error.get()
+        {
+            return Poll::Ready(Err(io::Error::other(message.clone())));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Streams opened by the other end.
+pub struct Incoming {
+    rx: mpsc::Receiver<IncomingStream>,
+}
+
+impl Incoming {
+    pub async fn accept(&mut self) -> Option<IncomingStream> {
+        self.rx.recv().await
+    }
+}
+
+pub struct IncomingStream {
+    /// Bridge arguments sent with `OPEN`.
+    pub target: Vec<String>,
+    pub io: MuxStream,
+    pub abort: StreamAbort,
+}
+
+/// Aborts an incoming stream with an `ERROR` frame.
+pub struct StreamAbort {
+    id: u32,
+    outgoing: mpsc::Sender<Frame>,
+}
+
+impl StreamAbort {
+    /// Reports `message` to the end that opened the stream.
+    pub async fn send(self, message: String) {
+        let _ = self
+            .outgoing
+            .send(Frame::Error {
+                id: self.id,
+                message: truncate_message(message),
+            })
+            .await;
+    }
+}
+
+impl Mux {
+    /// Starts a session over `reader`/`writer`. The session runs until the
+    /// reader reaches EOF or the channel fails.
+    pub fn start<R, W>(reader: R, writer: W, side: Side) -> (Mux, Incoming)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (outgoing, outgoing_rx) = mpsc::channel(64);
+        let (incoming_tx, incoming_rx) = mpsc::channel(16);
+        let mux = Mux {
+            side,
+            outgoing,
+            streams: Arc::default(),
+            next_id: Arc::new(AtomicU32::new(match side {
+                Side::Client => 1,
+                Side::Server => 2,
+            })),
+            closed: Arc::default(),
+        };
+
+        tokio::spawn(write_frames(writer, outgoing_rx, mux.closed.clone()));
+        tokio::spawn(mux.clone().read_frames(reader, incoming_tx));
+
+        (mux, Incoming { rx: incoming_rx })
+    }
+
+    /// True once the underlying channel has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Opens a stream to the other end, which runs the bridge described by
+    /// `target` and relays it to the returned endpoint.
+    pub async fn open(&self, target: Vec<String>) -> io::Result<MuxStream> {
+        if self.is_closed() {
+            return Err(closed());
+        }
+
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let open = Frame::Open { id, target };
+        open.encode(&mut Vec::new())?;
+
+        let user = self.spawn_stream(id);
+
+        if self.outgoing.send(open).await.is_err() {
+            self.streams.lock().unwrap().remove(&id);
+            return Err(closed());
+        }
+
+        Ok(user)
+    }
+
+    async fn read_frames<R: AsyncRead + Unpin>(
+        self,
+        mut reader: R,
+        incoming: mpsc::Sender<IncomingStream>,
+    ) {
+        while let Ok(Some(frame)) = read_frame(&mut reader).await {
+            match frame {
+                Frame::Open { id, target } => {
+                    if self.side.opens(id) {
+                        self.reset(id, format!("Stream {id} opened with one of our ids"))
+                            .await;
+                        continue;
+                    }
+                    if self.streams.lock().unwrap().contains_key(&id) {
+                        self.reset(id, format!("Stream {id} opened while already open"))
+                            .await;
+                        continue;
+                    }
+
+                    let stream = IncomingStream {
+                        target,
+                        io: self.spawn_stream(id),
+                        abort: StreamAbort {
+                            id,
+                            outgoing: self.outgoing.clone(),
+                        },
+                    };
+                    // Waiting for the stream to be accepted would hold up
+                    // every other stream.
+                    let (stream, message) = match incoming.try_send(stream) {
+                        Ok(()) => continue,
+                        Err(mpsc::error::TrySendError::Full(stream)) => {
+                            (stream, "Too many streams are waiting to be accepted")
+                        }
+                        Err(mpsc::error::TrySendError::Closed(stream)) => {
+                            (stream, "Streams are not accepted here")
+                        }
+                    };
+                    stream.abort.send(message.into()).await;
+                }
+                Frame::Data { id, payload } => {
+                    if let Err(message) = self.receive(id, payload) {
+                        self.reset(id, message).await;
+                    }
+                }
+                Frame::Close { id } => {
+                    if let Some(stream) = self.streams.lock().unwrap().get_mut(&id) {
+                        stream.inbound = None;
+                    }
+                }
+                Frame::Error { id, message } => {
+                    tracing::warn!(stream = id, "{message}");
+                    self.remove(id, message);
+                }
+                Frame::Window { id, increment } => {
+                    if let Err(message) = self.grant(id, increment) {
+                        self.reset(id, message).await;
+                    }
+                }
+            }
+        }
+
+        self.closed.store(true, Ordering::Release);
+        for (_, stream) in self.streams.lock().unwrap().drain() {
+            // Streams the other end had closed got all of their data.
+            if stream.inbound.is_some() {
+                let _ = stream.error.set(closed().to_string());
+            }
+            stream.send_window.close();
+        }
+    }
+
+    /// Passes `payload` on to stream `id`, if the other end had the window
+    /// to send it.
+    fn receive(&self, id: u32, payload: Vec<u8>) -> Result<(), String> {
+        let streams = self.streams.lock().unwrap();
+        let Some(stream) = streams.get(&id) else {
+            return Ok(());
+        };
+        let Some(inbound) = &stream.inbound else {
+            return Err("Data sent after CLOSE".into());
+        };
+        if payload.len() > stream.receive_window.load(Ordering::Acquire) {
+            return Err(format!(
+                "Data sent beyond the {STREAM_WINDOW} byte stream window"
+            ));
+        }
+
+        stream
+            .receive_window
+            .fetch_sub(payload.len(), Ordering::AcqRel);
+        let _ = inbound.send(payload);
+        Ok(())
+    }
+
+    /// Lets stream `id` send `increment` more bytes.
+    fn grant(&self, id: u32, increment: u32) -> Result<(), String> {
+        let streams = self.streams.lock().unwrap();
+        let Some(stream) = streams.get(&id) else {
+            return Ok(());
+        };
+        let increment = increment as usize;
+        if stream.send_window.available_permits() + increment > STREAM_WINDOW {
+            return Err(format!(
+                "Window grown beyond the {STREAM_WINDOW} byte stream window"
+            ));
+        }
+
+        stream.send_window.add_permits(increment);
+        Ok(())
+    }
+
+    /// Forgets stream `id`, ending both of its directions and failing
+    /// reads from it with `message`.
+    fn remove(&self, id: u32, message: String) {
+        if let Some(stream) = self.streams.lock().unwrap().remove(&id) {
+            let _ = stream.error.set(message);
+            stream.send_window.close();
+        }
+    }
+
+    /// Aborts stream `id` at both ends after the other end broke the
+    /// protocol on it.
+    async fn reset(&self, id: u32, message: String) {
+        tracing::warn!(stream = id, "{message}");
+        self.remove(id, message.clone());
+        let _ = self.outgoing.send(Frame::Error { id, message }).await;
+    }
+
+    /// Registers stream `id` and pumps it between the returned end and the
+    /// channel until both directions are finished.
+    fn spawn_stream(&self, id: u32) -> MuxStream {
+        let (user, local) = tokio::io::duplex(STREAM_BUFFER);
+        let (inbound, mut inbound_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let receive_window = Arc::new(AtomicUsize::new(STREAM_WINDOW));
+        let send_window = Arc::new(Semaphore::new(STREAM_WINDOW));
+        let error = Arc::new(OnceLock::new());
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamState {
+                inbound: Some(inbound),
+                receive_window: receive_window.clone(),
+                send_window: send_window.clone(),
+                error: error.clone(),
+            },
+        );
+
+        let outgoing = self.outgoing.clone();
+        let streams = self.streams.clone();
+        let registered = error.clone();
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(local);
+
+            let to_channel = async {
+                let mut buf = vec![0; CHUNK_SIZE];
+                while let Ok(n @ 1..) = reader.read(&mut buf).await {
+                    // Fails once the stream is aborted or the channel is gone.
+                    let Ok(permits) = send_window.acquire_many(n as u32).await else {
+                        return;
+                    };
+                    permits.forget();
+
+                    let payload = buf[..n].to_vec();
+                    if outgoing.send(Frame::Data { id, payload }).await.is_err() {
+                        return;
+                    }
+                }
+                let _ = outgoing.send(Frame::Close { id }).await;
+            };
+
+            let from_channel = async {
+                let mut broken = false;
+                while let Some(chunk) = inbound_rx.recv().await {
+                    // Keep draining after the local end is gone so the other
+                    // end is not left waiting for a window.
+                    if !broken && writer.write_all(&chunk).await.is_err() {
+                        broken = true;
+                    }
+
+                    receive_window.fetch_add(chunk.len(), Ordering::AcqRel);
+                    let increment = chunk.len() as u32;
+                    if outgoing
+                        .send(Frame::Window { id, increment })
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                let _ = writer.shutdown().await;
+            };
+
+            tokio::join!(to_channel, from_channel);
+            // The id may have been reset and opened again since.
+            let mut streams = streams.lock().unwrap();
+            if streams
+                .get(&id)
+                .is_some_and(|stream| Arc::ptr_eq(&stream.error, &registered))
+            {
+                streams.remove(&id);
+            }
+        });
+
+        MuxStream { io: user, error }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Multiplexer channel is closed")
+}
+
+async fn write_frames<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut frames: mpsc::Receiver<Frame>,
+    closed: Arc<AtomicBool>,
+) {
+    while let Some(frame) = frames.recv().await {
+        let mut written = write_frame(&mut writer, &frame).await;
+        if let Err(err) = &written
+            && err.get_ref().is_some_and(|err| err.is::<FrameError>())
+        {
+            // Nothing was written, and sending the frame would only make the
+            // other end drop the channel.
+            tracing::warn!(stream = frame.id(), %err, "dropped frame");
+            continue;
+        }
+        if written.is_ok() && frames.is_empty() {
+            written = writer.flush().await;
+        }
+        if written.is_err() {
+            break;
+        }
+    }
+
+    closed.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    async fn decode(mut buf: &[u8]) -> io::Result<Option<Frame>> {
+        read_frame(&mut buf).await
+    }
+
+    async fn round_trip(frame: Frame) {
+        let mut buf = Vec::new();
+        frame.encode(&mut buf).unwrap();
+        buf.extend_from_slice(b"trailing");
+
+        let mut reader = &buf[..];
+        let decoded = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(decoded, frame);
+        assert_eq!(reader, b"trailing");
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        round_trip(Frame::Open {
+            id: 1,
+            target: vec!["pipe".into(), "--name".into(), "//./pipe/x".into()],
+        })
+        .await;
+        round_trip(Frame::Data {
+            id: 7,
+            payload: vec![0, 1, 2, 255],
+        })
+        .await;
+        round_trip(Frame::Data {
+            id: u32::MAX,
+            payload: Vec::new(),
+        })
+        .await;
+        round_trip(Frame::Close { id: 3 }).await;
+        round_trip(Frame::Error {
+            id: 4,
+            message: "pipe not found".into(),
+        })
+        .await;
+        round_trip(Frame::Window {
+            id: 5,
+            increment: 16384,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors() {
+        let mut buf = Vec::new();
+        Frame::Data {
+            id: 1,
+            payload: b"hello".to_vec(),
+        }
+        .encode(&mut buf)
+        .unwrap();
+
+        assert!(decode(&[]).await.unwrap().is_none());
+        for len in 1..buf.len() {
+            let err = decode(&buf[..len]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_frames() {
+        let reason = |err: io::Error| *err.into_inner().unwrap().downcast::<FrameError>().unwrap();
+
+        let err = decode(&[9, 0, 0, 0, 1, 0, 0, 0, 0]).await.unwrap_err();
+        assert_eq!(reason(err), FrameError::UnknownKind(9));
+
+        let err = decode(&[KIND_DATA, 0, 0, 0, 1, 0, 1, 0, 1])
+            .await
+            .unwrap_err();
+        assert_eq!(reason(err), FrameError::TooLarge(MAX_PAYLOAD + 1));
+
+        let err = decode(&[KIND_ERROR, 0, 0, 0, 1, 0, 0, 0, 1, 0xff])
+            .await
+            .unwrap_err();
+        assert_eq!(reason(err), FrameError::InvalidUtf8);
+        let err = decode(&[KIND_WINDOW, 0, 0, 0, 1, 0, 0, 0, 2, 0, 1])
+            .await
+            .unwrap_err();
+        assert_eq!(reason(err), FrameError::InvalidWindow(2));
+    }
+
+    #[test]
+    fn refuses_to_encode_oversized_frames() {
+        let mut buf = Vec::new();
+        let open = Frame::Open {
+            id: 1,
+            target: vec!["x".repeat(MAX_PAYLOAD / 2), "x".repeat(MAX_PAYLOAD / 2)],
+        };
+        assert_eq!(
+            open.encode(&mut buf),
+            Err(FrameError::TooLarge(MAX_PAYLOAD + 1))
+        );
+        let error = Frame::Error {
+            id: 1,
+            message: "x".repeat(MAX_PAYLOAD + 1),
+        };
+        assert_eq!(
+            error.encode(&mut buf),
+            Err(FrameError::TooLarge(MAX_PAYLOAD + 1))
+        );
+        assert!(buf.is_empty());
+
+        assert_eq!(truncate_message("é".repeat(MAX_PAYLOAD)).len(), MAX_PAYLOAD);
+    }
+
+    #[tokio::test]
+    async fn reads_frames_from_a_stream() {
+        let (mut a, mut b) = duplex(1024);
+        let frames = [
+            Frame::Open {
+                id: 1,
+                target: vec!["gpg".into()],
+            },
+            Frame::Close { id: 1 },
+        ];
+        for frame in &frames {
+            write_frame(&mut a, frame).await.unwrap();
+        }
+        drop(a);
+
+        assert_eq!(read_frame(&mut b).await.unwrap(), Some(frames[0].clone()));
+        assert_eq!(read_frame(&mut b).await.unwrap(), Some(frames[1].clone()));
+        assert_eq!(read_frame(&mut b).await.unwrap(), None);
+    }
+
+    /// A client and server session joined back to back, with the server
+    /// answering every stream by echoing it back prefixed by its target.
+    fn echo_pair() -> Mux {
+        let (client_io, server_io) = duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client_io);
+        let (server_r, server_w) = tokio::io::split(server_io);
+
+        let (client, _) = Mux::start(client_r, client_w, Side::Client);
+        let (_server, mut incoming) = Mux::start(server_r, server_w, Side::Server);
+
+        tokio::spawn(async move {
+            while let Some(mut stream) = incoming.accept().await {
+                tokio::spawn(async move {
+                    if stream.target == ["fail"] {
+                        stream.abort.send("no such bridge".into()).await;
+                        return;
+                    }
+                    let prefix = stream.target.join(" ") + ":";
+                    stream.io.write_all(prefix.as_bytes()).await.unwrap();
+                    let mut request = Vec::new();
+                    stream.io.read_to_end(&mut request).await.unwrap();
+                    stream.io.write_all(&request).await.unwrap();
+                    stream.io.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        client
+    }
+
+    #[tokio::test]
+    async fn streams_are_independent() {
+        let client = echo_pair();
+
+        let mut first = client.open(vec!["one".into()]).await.unwrap();
+        let mut second = client.open(vec!["two".into(), "2".into()]).await.unwrap();
+
+        second.write_all(b"second request").await.unwrap();
+        second.shutdown().await.unwrap();
+        first.write_all(b"first request").await.unwrap();
+        first.shutdown().await.unwrap();
+
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "two 2:second request");
+
+        let mut reply = String::new();
+        first.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "one:first request");
+    }
+
+    #[tokio::test]
+    async fn large_transfers_are_chunked() {
+        let client = echo_pair();
+        let mut stream = client.open(vec!["big".into()]).await.unwrap();
+
+        let payload: Vec<u8> = (0..MAX_PAYLOAD * 3).map(|i| i as u8).collect();
+        let (mut reader, mut writer) = tokio::io::split(&mut stream);
+        let send = async {
+            writer.write_all(&payload).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let mut reply = Vec::new();
+        let receive = reader.read_to_end(&mut reply);
+        let (_, received) = tokio::join!(send, receive);
+        received.unwrap();
+
+        assert_eq!(&reply[..4], b"big:");
+        assert_eq!(&reply[4..], &payload[..]);
+    }
+
+    #[tokio::test]
+    async fn failed_streams_read_the_error() {
+        let client = echo_pair();
+        let mut stream = client.open(vec!["fail".into()]).await.unwrap();
+
+        let mut reply = Vec::new();
+        let err = stream.read_to_end(&mut reply).await.unwrap_err();
+        assert_eq!(err.to_string(), "no such bridge");
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn closed_channel_refuses_new_streams() {
+        let (client_io, server_io) = duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client_io);
+        let (client, _) = Mux::start(client_r, client_w, Side::Client);
+
+        drop(server_io);
+        while !client.is_closed() {
+            tokio::task::yield_now().await;
+        }
+
+        let err = client.open(vec!["gpg".into()]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn oversized_targets_are_refused() {
+        let client = echo_pair();
+        let err = client
+            .open(vec!["x".repeat(MAX_PAYLOAD + 1)])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut stream = client.open(vec!["small".into()]).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "small:");
+    }
+
+    #[tokio::test]
+    async fn stalled_streams_do_not_block_others() {
+        let (client_io, server_io) = duplex(4096);
+        let (client_r, client_w) = tokio::io::split(client_io);
+        let (server_r, server_w) = tokio::io::split(server_io);
+        let (client, _) = Mux::start(client_r, client_w, Side::Client);
+        let (_server, mut incoming) = Mux::start(server_r, server_w, Side::Server);
+
+        // The first stream is never read, the second one is echoed.
+        let server = tokio::spawn(async move {
+            let stalled = incoming.accept().await.unwrap();
+            let mut echoed = incoming.accept().await.unwrap();
+            let (mut reader, mut writer) = tokio::io::split(&mut echoed.io);
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            writer.shutdown().await.unwrap();
+            stalled
+        });
+
+        let mut stalled = client.open(vec!["stall".into()]).await.unwrap();
+        let flood = vec![0; STREAM_WINDOW * 2];
+        let flooding = tokio::spawn(async move {
+            let _ = stalled.write_all(&flood).await;
+            stalled
+        });
+
+        let mut echoed = client.open(vec!["echo".into()]).await.unwrap();
+        echoed.write_all(b"still here").await.unwrap();
+        echoed.shutdown().await.unwrap();
+        let mut reply = String::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            echoed.read_to_string(&mut reply),
+        )
+        .await
+        .expect("echoed stream is blocked")
+        .unwrap();
+        assert_eq!(reply, "still here");
+
+        assert!(!flooding.is_finished());
+        drop(server.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn streams_exceeding_their_window_are_reset() {
+        let (mut peer, mut incoming) = raw_server();
+
+        let data = Frame::Data {
+            id: 1,
+            payload: vec![0; CHUNK_SIZE],
+        };
+        let mut frames = vec![open(1)];
+        frames.extend(std::iter::repeat_n(data, STREAM_WINDOW / CHUNK_SIZE + 1));
+        send_frames(&mut peer, &frames).await;
+
+        let _stalled = incoming.accept().await.unwrap();
+        let reset = Frame::Error {
+            id: 1,
+            message: format!("Data sent beyond the {STREAM_WINDOW} byte stream window"),
+        };
+        assert_eq!(read_frame(&mut peer).await.unwrap(), Some(reset));
+    }
+
+    /// A server session driven by raw frames from the returned peer end.
+    fn raw_server() -> (DuplexStream, Incoming) {
+        let (peer, server_io) = duplex(STREAM_WINDOW * 2);
+        let (server_r, server_w) = tokio::io::split(server_io);
+        let (_server, incoming) = Mux::start(server_r, server_w, Side::Server);
+        (peer, incoming)
+    }
+
+    async fn send_frames(peer: &mut DuplexStream, frames: &[Frame]) {
+        let mut buf = Vec::new();
+        for frame in frames {
+            frame.encode(&mut buf).unwrap();
+        }
+        peer.write_all(&buf).await.unwrap();
+    }
+
+    fn open(id: u32) -> Frame {
+        Frame::Open {
+            id,
+            target: vec!["gpg".into()],
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_opened_with_taken_ids_are_reset() {
+        let (mut peer, mut incoming) = raw_server();
+
+        send_frames(&mut peer, &[open(1), open(1), open(2)]).await;
+
+        let mut first = incoming.accept().await.unwrap();
+        let duplicate = Frame::Error {
+            id: 1,
+            message: "Stream 1 opened while already open".into(),
+        };
+        assert_eq!(read_frame(&mut peer).await.unwrap(), Some(duplicate));
+        let wrong_side = Frame::Error {
+            id: 2,
+            message: "Stream 2 opened with one of our ids".into(),
+        };
+        assert_eq!(read_frame(&mut peer).await.unwrap(), Some(wrong_side));
+
+        let err = first.io.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.to_string(), "Stream 1 opened while already open");
+    }
+
+    #[tokio::test]
+    async fn streams_beyond_the_accept_queue_are_refused() {
+        let (mut peer, _incoming) = raw_server();
+
+        let opens: Vec<Frame> = (0..17).map(|n| open(2 * n + 1)).collect();
+        send_frames(&mut peer, &opens).await;
+
+        let refused = Frame::Error {
+            id: 33,
+            message: "Too many streams are waiting to be accepted".into(),
+        };
+        assert_eq!(read_frame(&mut peer).await.unwrap(), Some(refused));
+    }
+}
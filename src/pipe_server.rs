@@ -0,0 +1,52 @@
+//! Windows side of a reverse bridge: a named pipe server whose clients are
+//! relayed to a Unix socket in WSL, so Windows programs such as OpenSSH
+//! can use an agent running in WSL.
+//!
+//! Every accepted connection is relayed to a [`Backend`] started through
+//! `wsl.exe`: its own `relay stdio: unix:PATH` process, or a stream
+//! multiplexed over one long-running `serve` process.
+
+use crate::backend::Backend;
+use std::{io, sync::Arc};
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+
+/// Serves the pipe `name` until Ctrl+C, relaying each connection to
+/// `backend`.
+pub async fn serve(name: &str, backend: Backend) -> io::Result<()> {
+    // Refusing to share the name with an existing server keeps another
+    // process from answering some of our clients.
+    let server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(name)?;
+
+    tokio::select! {
+        result = accept_loop(name, server, Arc::new(backend)) => result,
+        result = tokio::signal::ctrl_c() => result,
+    }
+}
+
+/// Accepts clients on `server` forever, relaying each one to `backend`.
+///
+/// A pipe instance serves one client, so a new one is created for the next
+/// client before the connected one is handed off.
+async fn accept_loop(
+    name: &str,
+    mut server: NamedPipeServer,
+    backend: Arc<Backend>,
+) -> io::Result<()> {
+    loop {
+        server.connect().await?;
+        let client = std::mem::replace(&mut server, ServerOptions::new().create(name)?);
+
+        let backend = backend.clone();
+        let name = name.to_owned();
+        tokio::spawn(async move {
+            tracing::debug!(pipe = name, "accepted connection");
+            // Dropping the instance closes it without disconnecting, so the
+            // client can still read whatever the relay wrote last.
+            if let Err(err) = backend.relay(client, &[]).await {
+                tracing::warn!(pipe = name, %err, "connection failed");
+            }
+        });
+    }
+}
@@ -0,0 +1,364 @@
+//! Local policy for SSH agent requests.
+//!
+//! A [`Guarded`] agent answers requests the policy forbids with
+//! `SSH_AGENT_FAILURE` itself, so they never reach the Windows agent, and
+//! hides keys the policy does not expose from identity listings.
+
+use crate::ssh_agent::{self, Agent, Identity, Operation, Request, Response};
+use std::io;
+
+/// The operations `--read-only` allows: listing keys and signing with them.
+pub const READ_ONLY: &[Operation] = &[Operation::List, Operation::Sign];
+
+/// Which requests may be forwarded to the agent.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// `None` allows every request, including unknown message types.
+    allowed: Option<Vec<Operation>>,
+    keys: KeyFilter,
+}
+
+impl Policy {
+    /// A policy that only forwards `operations`. Requests of unknown type are
+    /// always refused.
+    pub fn allow(operations: &[Operation]) -> Self {
+        Self {
+            allowed: Some(operations.to_vec()),
+            keys: KeyFilter::default(),
+        }
+    }
+
+    /// Only exposes the keys matched by `keys`.
+    pub fn with_keys(mut self, keys: KeyFilter) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Whether this policy can refuse anything at all.
+    pub fn is_restricted(&self) -> bool {
+        self.allowed.is_some() || !self.keys.is_empty()
+    }
+
+    pub fn permits(&self, request: &Request) -> bool {
+        match &self.allowed {
+            None => true,
+            Some(allowed) => request
+                .operation()
+                .is_some_and(|operation| allowed.contains(&operation)),
+        }
+    }
+}
+
+/// Which keys are exposed to clients.
+///
+/// Every non-empty list has to match a key (so `types` and `comments` narrow
+/// each other down), while any one entry of a list is enough to match it.
+/// An empty filter exposes every key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyFilter {
+    /// `SHA256:...` fingerprints, as printed by `ssh-add -l`. The `SHA256:`
+    /// prefix is optional.
+    pub fingerprints: Vec<String>,
+    /// Key types such as `ssh-ed25519` or `ecdsa-sha2-nistp256`.
+    pub types: Vec<String>,
+    /// Patterns matched against the key comment, where `*` matches any run
+    /// of characters and `?` any single character.
+    pub comments: Vec<String>,
+}
+
+impl KeyFilter {
+    pub fn is_empty(&self) -> bool {
+        self.fingerprints.is_empty() && self.types.is_empty() && self.comments.is_empty()
+    }
+
+    pub fn permits(&self, identity: &Identity) -> bool {
+        let comment = String::from_utf8_lossy(&identity.comment);
+        self.permits_blob(&identity.key_blob)
+            && (self.comments.is_empty()
+                || self
+                    .comments
+                    .iter()
+                    .any(|pattern| glob_match(pattern, &comment)))
+    }
+
+    /// Checks everything that can be decided from the key itself.
+    fn permits_blob(&self, key_blob: &[u8]) -> bool {
+        let fingerprint_matches = || {
+            let fingerprint = ssh_agent::fingerprint(key_blob);
+            let hash = &fingerprint["SHA256:".len()..];
+            self.fingerprints
+                .iter()
+                .any(|wanted| wanted == &fingerprint || wanted == hash)
+        };
+        let type_matches = || {
+            ssh_agent::key_type(key_blob)
+                .is_some_and(|key_type| self.types.iter().any(|wanted| wanted == key_type))
+        };
+
+        (self.fingerprints.is_empty() || fingerprint_matches())
+            && (self.types.is_empty() || type_matches())
+    }
+}
+
+/// Matches `text` against a pattern where `*` matches any run of characters
+/// and `?` matches exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*`: its pattern index and the text
+    // index it currently stretches to.
+    let mut star = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// An agent that only sees the requests its policy permits.
+pub struct Guarded<A> {
+    agent: A,
+    policy: Policy,
+    /// Keys exposed by the most recent identity listing.
+    exposed: Vec<Vec<u8>>,
+}
+
+impl<A: Agent + Send> Guarded<A> {
+    pub fn new(agent: A, policy: Policy) -> Self {
+        Self {
+            agent,
+            policy,
+            exposed: Vec::new(),
+        }
+    }
+
+    /// Lists the agent's keys, keeping only those the filter exposes.
+    async fn list(&mut self) -> io::Result<Response> {
+        let response = self.agent.call(Request::RequestIdentities).await?;
+        let Response::IdentitiesAnswer(identities) = response else {
+            return Ok(response);
+        };
+
+        let identities: Vec<Identity> = identities
+            .into_iter()
+            .filter(|identity| self.policy.keys.permits(identity))
+            .collect();
+        self.exposed = identities.iter().map(|id| id.key_blob.clone()).collect();
+        Ok(Response::IdentitiesAnswer(identities))
+    }
+
+    /// Whether requests may use `key_blob`.
+    async fn key_permitted(&mut self, key_blob: &[u8]) -> io::Result<bool> {
+        let keys = &self.policy.keys;
+        if keys.is_empty() {
+            return Ok(true);
+        }
+        if keys.comments.is_empty() {
+            return Ok(keys.permits_blob(key_blob));
+        }
+
+        // Comments are only known to the agent, so look the key up there if
+        // the client did not list keys before using it.
+        if !self.exposed.iter().any(|exposed| exposed == key_blob) {
+            self.list().await?;
+        }
+        Ok(self.exposed.iter().any(|exposed| exposed == key_blob))
+    }
+}
+
+impl<A: Agent + Send> Agent for Guarded<A> {
+    async fn call(&mut self, request: Request) -> io::Result<Response> {
+        if !self.policy.permits(&request) {
+            tracing::info!(operation = ?request.operation(), "refused SSH agent request");
+            return Ok(Response::Failure);
+        }
+
+        if request == Request::RequestIdentities {
+            return self.list().await;
+        }
+        if let Request::SignRequest { key_blob, .. } | Request::RemoveIdentity { key_blob } =
+            &request
+            && !self.key_permitted(key_blob).await?
+        {
+            tracing::info!(
+                key = %ssh_agent::fingerprint(key_blob),
+                "refused SSH agent request for a hidden key"
+            );
+            return Ok(Response::Failure);
+        }
+
+        self.agent.call(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ssh_agent::testing::{FakeAgent, identity, key_blob, sign};
+
+    #[test]
+    fn unrestricted_policy_permits_everything() {
+        let policy = Policy::default();
+        assert!(!policy.is_restricted());
+        assert!(policy.permits(&Request::RemoveAllIdentities));
+        assert!(policy.permits(&Request::Unknown {
+            kind: 200,
+            body: vec![]
+        }));
+    }
+
+    #[tokio::test]
+    async fn refused_requests_are_answered_locally() {
+        let key = identity(1, "a");
+        let upstream = FakeAgent::new(1, vec![key.clone()]);
+        let mut agent = Guarded::new(upstream, Policy::allow(READ_ONLY));
+
+        let refused = [
+            Request::AddIdentity {
+                constrained: false,
+                key_type: "ssh-ed25519".into(),
+                rest: vec![],
+            },
+            Request::RemoveAllIdentities,
+            Request::Lock {
+                passphrase: b"x".to_vec(),
+            },
+            Request::Unknown {
+                kind: 200,
+                body: vec![],
+            },
+        ];
+
+        for request in refused {
+            assert_eq!(agent.call(request).await.unwrap(), Response::Failure);
+        }
+        assert_eq!(
+            agent.call(Request::RequestIdentities).await.unwrap(),
+            Response::IdentitiesAnswer(vec![key.clone()])
+        );
+        assert_eq!(
+            agent.call(sign(&key.key_blob)).await.unwrap(),
+            Response::SignResponse { signature: vec![1] }
+        );
+
+        assert_eq!(
+            agent.agent.requests,
+            [Request::RequestIdentities, sign(&key.key_blob)]
+        );
+    }
+
+    #[test]
+    fn key_filters_combine_lists_and_entries() {
+        let personal = identity(1, "me@home");
+        let work = identity(2, "me@work");
+        let work_rsa = Identity {
+            key_blob: key_blob("ssh-rsa", 3),
+            comment: b"me@work".to_vec(),
+        };
+
+        let filter = KeyFilter {
+            types: vec!["ssh-ed25519".into(), "ecdsa-sha2-nistp256".into()],
+            comments: vec!["*@work".into()],
+            ..KeyFilter::default()
+        };
+        assert!(!filter.permits(&personal));
+        assert!(filter.permits(&work));
+        assert!(!filter.permits(&work_rsa));
+
+        let fingerprint = ssh_agent::fingerprint(&personal.key_blob);
+        let by_fingerprint = KeyFilter {
+            fingerprints: vec![fingerprint.clone()],
+            ..KeyFilter::default()
+        };
+        assert!(by_fingerprint.permits(&personal));
+        assert!(!by_fingerprint.permits(&work));
+
+        let without_prefix = KeyFilter {
+            fingerprints: vec![fingerprint["SHA256:".len()..].into()],
+            ..KeyFilter::default()
+        };
+        assert!(without_prefix.permits(&personal));
+    }
+
+    #[tokio::test]
+    async fn filtered_keys_are_hidden_and_cannot_sign() {
+        let personal = identity(1, "me@home");
+        let work = identity(2, "me@work");
+        let keys = KeyFilter {
+            comments: vec!["*@work".into()],
+            ..KeyFilter::default()
+        };
+        let upstream = FakeAgent::new(1, vec![personal.clone(), work.clone()]);
+        let mut agent = Guarded::new(upstream, Policy::default().with_keys(keys));
+
+        // Signing before listing still looks the key's comment up.
+        assert_eq!(
+            agent.call(sign(&personal.key_blob)).await.unwrap(),
+            Response::Failure
+        );
+        assert_eq!(
+            agent.call(Request::RequestIdentities).await.unwrap(),
+            Response::IdentitiesAnswer(vec![work.clone()])
+        );
+        assert_eq!(
+            agent.call(sign(&work.key_blob)).await.unwrap(),
+            Response::SignResponse { signature: vec![1] }
+        );
+        assert_eq!(
+            agent
+                .call(Request::RemoveIdentity {
+                    key_blob: personal.key_blob.clone()
+                })
+                .await
+                .unwrap(),
+            Response::Failure
+        );
+
+        let forwarded: Vec<_> = agent
+            .agent
+            .requests
+            .iter()
+            .map(Request::operation)
+            .collect();
+        assert_eq!(
+            forwarded,
+            [
+                Some(Operation::List),
+                Some(Operation::List),
+                Some(Operation::Sign),
+                Some(Operation::List),
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*@work", "me@work"));
+        assert!(glob_match("me@*", "me@work"));
+        assert!(glob_match("m?@w*k", "me@work"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*@work", "me@work.example"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("a*b", "acbd"));
+    }
+}
@@ -0,0 +1,752 @@
+//! Bidirectional byte relay shared by every mode.
+//!
+//! A [`Relay`] joins two endpoints (anything that is both [`AsyncRead`] and
+//! [`AsyncWrite`]) and copies bytes in both directions until each side has
+//! reached EOF. When one side finishes sending, the write half of the other
+//! side is closed according to that endpoint's [`HalfClose`] behaviour so the
+//! peer observes the EOF as well.
+//!
+//! [`Timeouts`] end a session that has gone quiet or lasted too long, however
+//! its traffic is relayed. They only start once the session is connected, so
+//! retrying or launching its endpoint is limited by its own options instead.
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{oneshot, watch},
+    time::Instant,
+};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// How long to keep reading from a named pipe after our side has finished
+/// writing to it, unless `pipe --linger` says otherwise. Long enough for an
+/// agent that waits for the user to confirm a signature.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub const PIPE_LINGER: Duration = Duration::from_secs(60);
+
+/// What to do with an endpoint's write side once the opposite endpoint has
+/// reached EOF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HalfClose {
+    /// Flush and shut the write side down so the peer reads EOF, then keep
+    /// relaying its replies until it closes too. This is `shutdown(Write)`
+    /// for TCP and Unix sockets.
+    Shutdown,
+    /// Flush only. Named pipes cannot be half-closed, so the peer never sees
+    /// EOF; instead we keep relaying its replies until it has been quiet for
+    /// the given duration or disconnects.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Linger(Duration),
+}
+
+/// Which way bytes were flowing when something happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    AToB,
+    BToA,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::AToB => f.write_str("a -> b"),
+            Direction::BToA => f.write_str("b -> a"),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Relay {direction} failed: {source}")]
+pub struct RelayError {
+    pub direction: Direction,
+    #[source]
+    pub source: io::Error,
+}
+
+/// Bytes copied in each direction over the lifetime of a relay.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayStats {
+    pub a_to_b: u64,
+    pub b_to_a: u64,
+}
+
+pub struct Relay<A, B> {
+    a: A,
+    b: B,
+    a_close: HalfClose,
+    b_close: HalfClose,
+}
+
+impl<A, B> Relay<A, B>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    pub fn new(a: A, b: B) -> Self {
+        Self {
+            a,
+            b,
+            a_close: HalfClose::Shutdown,
+            b_close: HalfClose::Shutdown,
+        }
+    }
+
+    /// Sets how `b` is closed once `a` reaches EOF.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn half_close_b(mut self, close: HalfClose) -> Self {
+        self.b_close = close;
+        self
+    }
+
+    /// Copies bytes both ways until both directions are finished.
+    ///
+    /// If either direction fails the other one is abandoned and the error is
+    /// returned; disconnects are treated as a normal EOF (see
+    /// [`is_disconnect`]).
+    pub async fn run(self) -> Result<RelayStats, RelayError> {
+        let (mut a_reader, mut a_writer) = tokio::io::split(self.a);
+        let (mut b_reader, mut b_writer) = tokio::io::split(self.b);
+        let (a_linger_tx, a_linger_rx) = oneshot::channel();
+        let (b_linger_tx, b_linger_rx) = oneshot::channel();
+
+        let a_to_b = async {
+            let a_to_b = Pump {
+                close: self.b_close,
+                peer_linger: b_linger_tx,
+                linger: a_linger_rx,
+            };
+            a_to_b
+                .run(&mut a_reader, &mut b_writer)
+                .await
+                .map_err(|source| RelayError {
+                    direction: Direction::AToB,
+                    source,
+                })
+        };
+        let b_to_a = async {
+            let b_to_a = Pump {
+                close: self.a_close,
+                peer_linger: a_linger_tx,
+                linger: b_linger_rx,
+            };
+            b_to_a
+                .run(&mut b_reader, &mut a_writer)
+                .await
+                .map_err(|source| RelayError {
+                    direction: Direction::BToA,
+                    source,
+                })
+        };
+
+        let (a_to_b, b_to_a) = tokio::try_join!(a_to_b, b_to_a)?;
+        tracing::debug!(a_to_b, b_to_a, "relay finished");
+        Ok(RelayStats { a_to_b, b_to_a })
+    }
+}
+
+/// Returns true for errors that only mean the peer went away.
+pub fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    ) || is_pipe_disconnect(err)
+}
+
+#[cfg(windows)]
+fn is_pipe_disconnect(err: &io::Error) -> bool {
+    use windows_sys::Win32::Foundation::{ERROR_NO_DATA, ERROR_PIPE_NOT_CONNECTED};
+
+    matches!(
+        err.raw_os_error(),
+        Some(code) if code == ERROR_NO_DATA as i32 || code == ERROR_PIPE_NOT_CONNECTED as i32
+    )
+}
+
+#[cfg(not(windows))]
+fn is_pipe_disconnect(_err: &io::Error) -> bool {
+    false
+}
+
+/// One direction of a relay.
+struct Pump {
+    /// How to close our writer once our reader reaches EOF.
+    close: HalfClose,
+    /// Tells the opposite direction to stop after going idle, used when our
+    /// writer could not be shut down.
+    peer_linger: oneshot::Sender<Duration>,
+    /// Set by the opposite direction when we should stop after going idle.
+    linger: oneshot::Receiver<Duration>,
+}
+
+impl Pump {
+    /// Copies `reader` into `writer` until EOF, then closes the writer.
+    async fn run<R, W>(mut self, reader: &mut R, writer: &mut W) -> io::Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut total = 0;
+        let mut idle_limit = None;
+        let mut linger_resolved = false;
+
+        loop {
+            let read = match idle_limit {
+                Some(limit) => match tokio::time::timeout(limit, reader.read(&mut buf)).await {
+                    Ok(read) => read,
+                    Err(_) => break,
+                },
+                None => tokio::select! {
+                    read = reader.read(&mut buf) => read,
+                    limit = &mut self.linger, if !linger_resolved => {
+                        linger_resolved = true;
+                        idle_limit = limit.ok();
+                        continue;
+                    }
+                },
+            };
+
+            let n = match read {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if is_disconnect(&err) => break,
+                Err(err) => return Err(err),
+            };
+
+            match write_chunk(writer, &buf[..n]).await {
+                Ok(()) => total += n as u64,
+                Err(err) if is_disconnect(&err) => return Ok(total),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let closed = match self.close {
+            HalfClose::Shutdown => writer.shutdown().await,
+            HalfClose::Linger(limit) => {
+                let _ = self.peer_linger.send(limit);
+                writer.flush().await
+            }
+        };
+
+        match closed {
+            Err(err) if !is_disconnect(&err) => Err(err),
+            _ => Ok(total),
+        }
+    }
+}
+
+/// Limits on how long a session may run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// End the session after this long without bytes in either direction.
+    pub idle: Option<Duration>,
+    /// End the session after this long, busy or not.
+    pub session: Option<Duration>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    #[error("No traffic for {0:?}, closing the session")]
+    Idle(Duration),
+
+    #[error("Session lasted {0:?}, closing it")]
+    Session(Duration),
+}
+
+impl Timeouts {
+    /// Runs `session` until it finishes or a timeout fires, in which case it
+    /// is dropped. Traffic is whatever passes through streams wrapped by
+    /// [`Tracked::new`] for `activity`, and the timeouts start once
+    /// `session` calls [`Activity::connected`].
+    pub async fn enforce<F: Future>(
+        &self,
+        activity: &Activity,
+        session: F,
+    ) -> Result<F::Output, Timeout> {
+        let session_limit = async {
+            match self.session {
+                Some(limit) => tokio::time::sleep(limit).await,
+                None => std::future::pending().await,
+            }
+        };
+        let idle_limit = async {
+            let Some(limit) = self.idle else {
+                return std::future::pending().await;
+            };
+            loop {
+                let deadline = activity.last() + limit;
+                if Instant::now() >= deadline {
+                    return;
+                }
+                tokio::time::sleep_until(deadline).await;
+            }
+        };
+        let limits = async {
+            // The sender lives in `activity`, so this only returns once set.
+            let _ = activity.connected.subscribe().wait_for(|&set| set).await;
+            tokio::select! {
+                () = session_limit => Timeout::Session(self.session.unwrap_or_default()),
+                () = idle_limit => Timeout::Idle(self.idle.unwrap_or_default()),
+            }
+        };
+
+        let timeout = tokio::select! {
+            output = session => return Ok(output),
+            timeout = limits => timeout,
+        };
+        tracing::info!("{timeout}");
+        Err(timeout)
+    }
+}
+
+/// When bytes last passed through the [`Tracked`] streams sharing it.
+#[derive(Debug)]
+pub struct Activity {
+    start: Instant,
+    /// Time since `start` in nanoseconds.
+    last: AtomicU64,
+    /// Whether the session has connected its endpoints.
+    connected: watch::Sender<bool>,
+}
+
+impl Activity {
+    /// Marks the session as connected, starting its [`Timeouts`] from now.
+    pub fn connected(&self) {
+        self.touch();
+        self.connected.send_replace(true);
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_nanos() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_nanos(self.last.load(Ordering::Relaxed))
+    }
+}
+
+/// A stream that records when bytes were last read from or written to it.
+pub struct Tracked<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S) -> (Self, Arc<Activity>) {
+        let activity = Arc::new(Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            connected: watch::Sender::new(false),
+        });
+        let tracked = Self {
+            inner,
+            activity: activity.clone(),
+        };
+        (tracked, activity)
+    }
+
+    /// Like [`Tracked::new`], but recording traffic in an existing
+    /// `activity`.
+    pub fn sharing(inner: S, activity: &Arc<Activity>) -> Self {
+        Self {
+            inner,
+            activity: activity.clone(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_chunk<W: AsyncWrite + Unpin>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    writer.write_all(chunk).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn copies_both_directions() {
+        let (a, mut a_peer) = duplex(64);
+        let (b, mut b_peer) = duplex(64);
+        let relay = tokio::spawn(Relay::new(a, b).run());
+
+        a_peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        b_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        b_peer.write_all(b"pong!").await.unwrap();
+        let mut buf = [0; 5];
+        a_peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong!");
+
+        drop(a_peer);
+        drop(b_peer);
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(
+            stats,
+            RelayStats {
+                a_to_b: 4,
+                b_to_a: 5
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn propagates_eof_to_the_other_side() {
+        let (a, mut a_peer) = duplex(64);
+        let (b, mut b_peer) = duplex(64);
+        let relay = tokio::spawn(Relay::new(a, b).run());
+
+        a_peer.write_all(b"request").await.unwrap();
+        a_peer.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        b_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"request");
+
+        drop(b_peer);
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats.a_to_b, 7);
+    }
+
+    #[tokio::test]
+    async fn copies_more_than_one_buffer() {
+        let (a, mut a_peer) = duplex(1024);
+        let (b, mut b_peer) = duplex(1024);
+        let relay = tokio::spawn(Relay::new(a, b).run());
+
+        let payload: Vec<u8> = (0..BUFFER_SIZE * 3).map(|i| i as u8).collect();
+        let writer = {
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                a_peer.write_all(&payload).await.unwrap();
+                a_peer.shutdown().await.unwrap();
+                a_peer
+            })
+        };
+
+        let mut received = Vec::new();
+        b_peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+
+        drop(writer.await.unwrap());
+        drop(b_peer);
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats.a_to_b, payload.len() as u64);
+    }
+
+    /// Request, EOF, response: the upstream only answers once it has seen
+    /// the whole request.
+    #[tokio::test]
+    async fn request_eof_response_over_shutdown() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let relay = tokio::spawn(Relay::new(a, b).run());
+
+        let server = tokio::spawn(async move {
+            let mut request = Vec::new();
+            upstream.read_to_end(&mut request).await.unwrap();
+            upstream.write_all(b"response").await.unwrap();
+            upstream.shutdown().await.unwrap();
+            request
+        });
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        assert_eq!(server.await.unwrap(), b"request");
+
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(
+            stats,
+            RelayStats {
+                a_to_b: 7,
+                b_to_a: 8
+            }
+        );
+    }
+
+    /// Like a named pipe, the upstream never sees EOF and never closes; the
+    /// relay must still deliver the response and then finish on its own.
+    #[tokio::test(start_paused = true)]
+    async fn request_eof_response_over_linger() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let relay = tokio::spawn(
+            Relay::new(a, b)
+                .half_close_b(HalfClose::Linger(PIPE_LINGER))
+                .run(),
+        );
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = [0; 7];
+        upstream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"request");
+        tokio::time::sleep(PIPE_LINGER / 2).await;
+        upstream.write_all(b"response").await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let stats = relay.await.unwrap().unwrap();
+        assert_eq!(stats.b_to_a, 8);
+        drop(upstream);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn linger_waits_for_slow_responses_in_pieces() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let relay = tokio::spawn(
+            Relay::new(a, b)
+                .half_close_b(HalfClose::Linger(PIPE_LINGER))
+                .run(),
+        );
+
+        client.write_all(b"req").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = [0; 3];
+        upstream.read_exact(&mut request).await.unwrap();
+        for chunk in [&b"res"[..], b"pon", b"se"] {
+            tokio::time::sleep(PIPE_LINGER * 3 / 4).await;
+            upstream.write_all(chunk).await.unwrap();
+        }
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn default_linger_waits_for_confirmed_responses() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let relay = tokio::spawn(
+            Relay::new(a, b)
+                .half_close_b(HalfClose::Linger(PIPE_LINGER))
+                .run(),
+        );
+
+        client.write_all(b"sign").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = [0; 4];
+        upstream.read_exact(&mut request).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        upstream.write_all(b"signature").await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"signature");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn linger_ends_early_when_upstream_disconnects() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let relay = tokio::spawn(
+            Relay::new(a, b)
+                .half_close_b(HalfClose::Linger(Duration::from_secs(3600)))
+                .run(),
+        );
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut request = [0; 7];
+        upstream.read_exact(&mut request).await.unwrap();
+        upstream.write_all(b"bye").await.unwrap();
+        drop(upstream);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"bye");
+        relay.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_timeout_counts_traffic_both_ways() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let (a, activity) = Tracked::new(a);
+        activity.connected();
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            session: None,
+        };
+        let start = Instant::now();
+        let relay = tokio::spawn(async move {
+            timeouts
+                .enforce(&activity, Relay::new(a, b).run())
+                .await
+                .map(|_| ())
+        });
+
+        let mut buf = [0; 4];
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_secs(8)).await;
+            client.write_all(b"ping").await.unwrap();
+            upstream.read_exact(&mut buf).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(8)).await;
+            upstream.write_all(b"pong").await.unwrap();
+            client.read_exact(&mut buf).await.unwrap();
+        }
+
+        assert_eq!(
+            relay.await.unwrap(),
+            Err(Timeout::Idle(Duration::from_secs(10)))
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(48 + 10));
+        // The relay was dropped, so the client sees the connection end.
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn session_timeout_ends_busy_sessions() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let (a, activity) = Tracked::new(a);
+        activity.connected();
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            session: Some(Duration::from_secs(60)),
+        };
+        let relay = tokio::spawn(async move {
+            timeouts
+                .enforce(&activity, Relay::new(a, b).run())
+                .await
+                .map(|_| ())
+        });
+
+        let start = Instant::now();
+        let mut buf = [0; 4];
+        while client.write_all(b"ping").await.is_ok() {
+            if upstream.read_exact(&mut buf).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+
+        assert_eq!(
+            relay.await.unwrap(),
+            Err(Timeout::Session(Duration::from_secs(60)))
+        );
+        assert!(start.elapsed() <= Duration::from_secs(65));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_start_once_connected() {
+        let (_, activity) = Tracked::new(duplex(64).0);
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_secs(10)),
+            session: Some(Duration::from_secs(20)),
+        };
+        let start = Instant::now();
+        let session = async {
+            // Retrying the endpoint takes longer than either timeout.
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            activity.connected();
+            std::future::pending::<()>().await
+        };
+
+        assert_eq!(
+            timeouts.enforce(&activity, session).await,
+            Err(Timeout::Idle(Duration::from_secs(10)))
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(60 + 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sessions_without_timeouts_run_to_the_end() {
+        let (a, mut client) = duplex(64);
+        let (b, mut upstream) = duplex(64);
+        let (a, activity) = Tracked::new(a);
+        activity.connected();
+        let relay = tokio::spawn(async move {
+            Timeouts::default()
+                .enforce(&activity, Relay::new(a, b).run())
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_secs(24 * 3600)).await;
+        client.write_all(b"late").await.unwrap();
+        let mut buf = [0; 4];
+        upstream.read_exact(&mut buf).await.unwrap();
+        drop(client);
+        drop(upstream);
+        let stats = relay.await.unwrap().unwrap().unwrap();
+        assert_eq!(stats.a_to_b, 4);
+    }
+
+    #[test]
+    fn classifies_disconnects() {
+        assert!(is_disconnect(&io::ErrorKind::BrokenPipe.into()));
+        assert!(is_disconnect(&io::ErrorKind::ConnectionReset.into()));
+        assert!(!is_disconnect(&io::ErrorKind::PermissionDenied.into()));
+    }
+}
@@ -0,0 +1,316 @@
+//! Retrying connections with exponential backoff.
+//!
+//! Pipes that are busy or not there yet, socket files that name an agent
+//! which just restarted and agents that are still starting are all waited
+//! for with a [`RetryPolicy`].
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// How long to wait between attempts, and when to give up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay after the first failed attempt.
+    pub initial: Duration,
+    /// Factor the delay grows by after every further attempt, at least 1.
+    pub multiplier: f64,
+    /// Upper bound of the delay, before jitter.
+    pub max_delay: Duration,
+    /// Fraction between 0 and 1 by which each delay is randomly shortened
+    /// or lengthened, so that relays started together do not retry in step.
+    pub jitter: f64,
+    /// Give up once this much time has passed since the first attempt.
+    pub deadline: Option<Duration>,
+    /// Give up after this many attempts, counting the first one.
+    pub max_attempts: Option<u32>,
+}
+
+impl RetryPolicy {
+    /// Retries every `delay` until `deadline` has passed.
+    pub fn constant(delay: Duration, deadline: Duration) -> Self {
+        Self {
+            initial: delay,
+            multiplier: 1.0,
+            max_delay: delay,
+            jitter: 0.0,
+            deadline: Some(deadline),
+            max_attempts: None,
+        }
+    }
+
+    /// The delay after failed attempt `attempt` (counting from 1), given a
+    /// `random` number in `0.0..1.0`.
+    pub fn delay(&self, attempt: u32, random: f64) -> Duration {
+        let growth = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let delay = self
+            .initial
+            .mul_f64(growth.min(u32::MAX.into()))
+            .min(self.max_delay);
+        delay.mul_f64(1.0 + self.jitter * (2.0 * random - 1.0))
+    }
+
+    /// Calls `attempt` until it succeeds, fails with an error `retriable`
+    /// does not accept, or the policy gives up. In the last case the most
+    /// recent error is returned.
+    pub async fn retry<T, E, F>(
+        &self,
+        mut attempt: impl FnMut() -> F,
+        retriable: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        E: fmt::Display,
+        F: Future<Output = Result<T, E>>,
+    {
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        let mut random = Random::new();
+
+        for attempts in 1.. {
+            let err = match attempt().await {
+                Err(err) if retriable(&err) => err,
+                result => return result,
+            };
+            if self.max_attempts.is_some_and(|max| attempts >= max) {
+                tracing::debug!(attempts, %err, "giving up after the last attempt");
+                return Err(err);
+            }
+
+            let mut delay = self.delay(attempts, random.next());
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    tracing::debug!(attempts, %err, "giving up at the deadline");
+                    return Err(err);
+                }
+                delay = delay.min(left);
+            }
+            tracing::debug!(attempt = attempts, ?delay, %err, "retrying");
+            tokio::time::sleep(delay).await;
+        }
+        unreachable!("retried more than u32::MAX times")
+    }
+}
+
+/// Longest duration accepted, so that deadlines and delays computed from it
+/// cannot overflow.
+const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Parses a duration such as `250ms`, `2s`, `1m` or `8h`, of at most a year.
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {text:?}, expected e.g. 250ms, 2s or 1m"))?;
+
+    let duration = match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => number.checked_mul(60).map(Duration::from_secs),
+        "h" => number.checked_mul(3600).map(Duration::from_secs),
+        _ => {
+            return Err(format!(
+                "invalid duration unit in {text:?}, expected ms, s, m or h"
+            ));
+        }
+    };
+
+    duration
+        .filter(|duration| *duration <= MAX_DURATION)
+        .ok_or_else(|| format!("duration {text:?} is too long, at most 8760h is allowed"))
+}
+
+pub fn check_multiplier(multiplier: f64) -> Result<f64, String> {
+    if multiplier.is_finite() && multiplier >= 1.0 {
+        Ok(multiplier)
+    } else {
+        Err(format!("multiplier must be at least 1, got {multiplier}"))
+    }
+}
+
+pub fn check_jitter(jitter: f64) -> Result<f64, String> {
+    if (0.0..=1.0).contains(&jitter) {
+        Ok(jitter)
+    } else {
+        Err(format!("jitter must be between 0 and 1, got {jitter}"))
+    }
+}
+
+pub fn parse_multiplier(text: &str) -> Result<f64, String> {
+    check_multiplier(
+        text.parse()
+            .map_err(|_| format!("invalid number {text:?}"))?,
+    )
+}
+
+pub fn parse_jitter(text: &str) -> Result<f64, String> {
+    check_jitter(
+        text.parse()
+            .map_err(|_| format!("invalid number {text:?}"))?,
+    )
+}
+
+/// Jitter does not need good randomness, just different values in every
+/// process, so a xorshift generator seeded by std's hasher keys does.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Self {
+        Self(RandomState::new().build_hasher().finish() | 1)
+    }
+
+    /// A number in `0.0..1.0`.
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(500),
+            jitter: 0.0,
+            deadline: None,
+            max_attempts: None,
+        }
+    }
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| policy().delay(attempt, 0.5).as_millis())
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+
+        let jittered = RetryPolicy {
+            jitter: 0.5,
+            ..policy()
+        };
+        assert_eq!(jittered.delay(2, 0.0), Duration::from_millis(100));
+        assert_eq!(jittered.delay(2, 0.5), Duration::from_millis(200));
+        assert_eq!(jittered.delay(2, 0.75), Duration::from_millis(250));
+        assert_eq!(jittered.delay(1000, 0.5), Duration::from_millis(500));
+
+        let mut random = Random::new();
+        assert!(
+            (0..1000)
+                .map(|_| random.next())
+                .all(|r| (0.0..1.0).contains(&r))
+        );
+    }
+
+    /// Runs `policy` against an attempt that fails with `NotFound` until
+    /// attempt `succeed_at`, returning the result, the number of attempts
+    /// and how much (paused) time passed.
+    async fn run(
+        policy: &RetryPolicy,
+        succeed_at: u32,
+    ) -> (Result<u32, io::ErrorKind>, u32, Duration) {
+        let start = Instant::now();
+        let mut attempts = 0;
+        let result = policy
+            .retry(
+                || {
+                    attempts += 1;
+                    let result = match attempts {
+                        n if n >= succeed_at => Ok(n),
+                        _ => Err(io::ErrorKind::NotFound),
+                    };
+                    async move { result }
+                },
+                |err| *err == io::ErrorKind::NotFound,
+            )
+            .await;
+        (result, attempts, start.elapsed())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff_until_success() {
+        let (result, attempts, elapsed) = run(&policy(), 5).await;
+        assert_eq!(result, Ok(5));
+        assert_eq!(attempts, 5);
+        assert_eq!(elapsed, Duration::from_millis(100 + 200 + 400 + 500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_at_the_attempt_limit_or_deadline() {
+        let limited = RetryPolicy {
+            max_attempts: Some(3),
+            ..policy()
+        };
+        let (result, attempts, elapsed) = run(&limited, u32::MAX).await;
+        assert_eq!(result, Err(io::ErrorKind::NotFound));
+        assert_eq!(attempts, 3);
+        assert_eq!(elapsed, Duration::from_millis(300));
+
+        // The last attempt happens right at the deadline.
+        let deadline = RetryPolicy {
+            deadline: Some(Duration::from_millis(1000)),
+            ..policy()
+        };
+        let (result, attempts, elapsed) = run(&deadline, u32::MAX).await;
+        assert_eq!(result, Err(io::ErrorKind::NotFound));
+        assert_eq!(attempts, 5);
+        assert_eq!(elapsed, Duration::from_millis(1000));
+
+        let constant = RetryPolicy::constant(Duration::from_millis(50), Duration::from_secs(1));
+        let (result, attempts, _) = run(&constant, u32::MAX).await;
+        assert_eq!(result, Err(io::ErrorKind::NotFound));
+        assert_eq!(attempts, 21);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn returns_other_errors_straight_away() {
+        let start = Instant::now();
+        let result: Result<(), _> = policy()
+            .retry(
+                || async { Err(io::ErrorKind::PermissionDenied) },
+                |err| *err == io::ErrorKind::NotFound,
+            )
+            .await;
+        assert_eq!(result, Err(io::ErrorKind::PermissionDenied));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("8h"), Ok(Duration::from_secs(8 * 3600)));
+        assert!(parse_duration("2").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("1.5s").is_err());
+        assert_eq!(parse_duration("8760h"), Ok(MAX_DURATION));
+        for too_long in ["8761h", "18446744073709551615s", "9999999999999999999h"] {
+            assert_eq!(
+                parse_duration(too_long),
+                Err(format!(
+                    "duration {too_long:?} is too long, at most 8760h is allowed"
+                ))
+            );
+        }
+
+        assert_eq!(parse_multiplier("1.5"), Ok(1.5));
+        assert!(parse_multiplier("0.5").is_err());
+        assert_eq!(parse_jitter("0.2"), Ok(0.2));
+        assert!(parse_jitter("1.5").is_err());
+        assert!(parse_jitter("x").is_err());
+    }
+}
@@ -0,0 +1,348 @@
+//! Socket files that stand in for Unix sockets on Windows.
+//!
+//! GnuPG on Windows listens on a TCP port on localhost and writes how to reach
+//! it into a file named like the socket, e.g. `S.gpg-agent`. Which format the
+//! file has depends on how GnuPG was built:
+//!
+//! - libassuan (Gpg4win): the port in decimal, a newline, then a 16 byte
+//!   nonce that has to be sent first on every connection.
+//! - Cygwin: `!<socket >PORT s XXXXXXXX-XXXXXXXX-XXXXXXXX-XXXXXXXX`, where the
+//!   four hex words are a secret the peers exchange before trading
+//!   credentials.
+//! - MSYS: `!<socket >PORT` on its own, with nothing to send.
+
+use std::{fmt, io, process};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const CYGWIN_MAGIC: &[u8] = b"!<socket >";
+const NONCE_LEN: usize = 16;
+/// `struct ucred` as exchanged by Cygwin: pid, uid and gid, 32 bits each.
+const CYGWIN_CRED_LEN: usize = 12;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum SocketFileError {
+    #[error("Invalid port {0:?} in socket file")]
+    InvalidPort(String),
+
+    #[error("Invalid number of bytes {0} expected {NONCE_LEN} bytes")]
+    InvalidNonce(usize),
+
+    #[error("Invalid secret {0:?} in Cygwin socket file")]
+    InvalidSecret(String),
+
+    #[error("Unsupported socket type {0:?} in Cygwin socket file, expected \"s\"")]
+    UnsupportedType(String),
+}
+
+/// Which program wrote a socket file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Libassuan,
+    Cygwin,
+    Msys,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Libassuan => f.write_str("libassuan"),
+            Format::Cygwin => f.write_str("Cygwin"),
+            Format::Msys => f.write_str("MSYS"),
+        }
+    }
+}
+
+/// The parsed contents of a socket file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketFile {
+    Libassuan { port: u16, nonce: [u8; NONCE_LEN] },
+    Cygwin { port: u16, secret: [u8; NONCE_LEN] },
+    Msys { port: u16 },
+}
+
+impl SocketFile {
+    pub fn parse(contents: &[u8]) -> Result<SocketFile, SocketFileError> {
+        match contents.strip_prefix(CYGWIN_MAGIC) {
+            Some(rest) => parse_cygwin(rest),
+            None => parse_libassuan(contents),
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        match self {
+            SocketFile::Libassuan { .. } => Format::Libassuan,
+            SocketFile::Cygwin { .. } => Format::Cygwin,
+            SocketFile::Msys { .. } => Format::Msys,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            SocketFile::Libassuan { port, .. }
+            | SocketFile::Cygwin { port, .. }
+            | SocketFile::Msys { port } => *port,
+        }
+    }
+
+    /// Connects to the port and performs whatever handshake the format needs,
+    /// leaving a stream that carries the protocol itself.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(("localhost", self.port())).await?;
+
+        match self {
+            SocketFile::Libassuan { nonce, .. } => stream.write_all(nonce).await?,
+            SocketFile::Cygwin { secret, .. } => cygwin_handshake(&mut stream, secret).await?,
+            SocketFile::Msys { .. } => {}
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Waits until the server has sent something, without consuming it.
+///
+/// An Assuan server greets every new connection, while gpg-agent closes
+/// connections that sent the wrong nonce straight away. A connection that
+/// ends before the greeting therefore reached an agent that has since
+/// written a new socket file.
+pub async fn await_greeting(stream: &TcpStream) -> io::Result<()> {
+    match stream.peek(&mut [0; 1]).await? {
+        0 => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed before the greeting, the nonce is probably stale",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn parse_port(text: &[u8]) -> Result<u16, SocketFileError> {
+    let invalid = || SocketFileError::InvalidPort(String::from_utf8_lossy(text).into_owned());
+    std::str::from_utf8(text)
+        .map_err(|_| invalid())?
+        .trim()
+        .parse()
+        .map_err(|_| invalid())
+}
+
+fn parse_libassuan(contents: &[u8]) -> Result<SocketFile, SocketFileError> {
+    let newline = contents
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or(SocketFileError::InvalidNonce(0))?;
+    let port = parse_port(&contents[..newline])?;
+
+    // libassuan reads just the nonce, so whatever follows it (a trailing
+    // newline from an editor, say) does not matter.
+    let nonce = &contents[newline + 1..];
+    let nonce = nonce
+        .first_chunk()
+        .copied()
+        .ok_or(SocketFileError::InvalidNonce(nonce.len()))?;
+
+    Ok(SocketFile::Libassuan { port, nonce })
+}
+
+fn parse_cygwin(rest: &[u8]) -> Result<SocketFile, SocketFileError> {
+    // Cygwin writes the terminating NUL as well.
+    let rest = rest.strip_suffix(b"\0").unwrap_or(rest);
+    let text = String::from_utf8_lossy(rest);
+    let mut fields = text.split_ascii_whitespace();
+
+    let port = parse_port(fields.next().unwrap_or_default().as_bytes())?;
+    let Some(kind) = fields.next() else {
+        return Ok(SocketFile::Msys { port });
+    };
+    if kind != "s" {
+        return Err(SocketFileError::UnsupportedType(kind.into()));
+    }
+
+    let guid = fields.next().unwrap_or_default();
+    let invalid = || SocketFileError::InvalidSecret(guid.into());
+    let words: Vec<&str> = guid.split('-').collect();
+    if words.len() != 4 || fields.next().is_some() {
+        return Err(invalid());
+    }
+
+    // The secret goes over the wire as four native (little endian) words.
+    let mut secret = [0; NONCE_LEN];
+    for (chunk, word) in secret.chunks_exact_mut(4).zip(words) {
+        if word.len() != 8 {
+            return Err(invalid());
+        }
+        let word = u32::from_str_radix(word, 16).map_err(|_| invalid())?;
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+
+    Ok(SocketFile::Cygwin { port, secret })
+}
+
+/// The connecting side of Cygwin's AF_UNIX emulation: trade the secret, then
+/// trade credentials.
+async fn cygwin_handshake(stream: &mut TcpStream, secret: &[u8; NONCE_LEN]) -> io::Result<()> {
+    stream.write_all(secret).await?;
+    let mut echoed = [0; NONCE_LEN];
+    stream.read_exact(&mut echoed).await?;
+    if &echoed != secret {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Cygwin socket answered with the wrong secret",
+        ));
+    }
+
+    let mut cred = [0; CYGWIN_CRED_LEN];
+    cred[..4].copy_from_slice(&process::id().to_le_bytes());
+    stream.write_all(&cred).await?;
+    stream.read_exact(&mut cred).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const LIBASSUAN: &[u8] = include_bytes!("../testdata/socket-files/libassuan");
+    const CYGWIN: &[u8] = include_bytes!("../testdata/socket-files/cygwin");
+    const MSYS: &[u8] = include_bytes!("../testdata/socket-files/msys");
+
+    #[test]
+    fn recognises_each_format() {
+        let libassuan = SocketFile::parse(LIBASSUAN).unwrap();
+        assert_eq!(libassuan.format(), Format::Libassuan);
+        assert_eq!(
+            libassuan,
+            SocketFile::Libassuan {
+                port: 54321,
+                nonce: *b"0123456789abcdef",
+            }
+        );
+
+        let trailing = [LIBASSUAN, b"\n"].concat();
+        assert_eq!(SocketFile::parse(&trailing), Ok(libassuan));
+
+        let cygwin = SocketFile::parse(CYGWIN).unwrap();
+        assert_eq!(cygwin.format(), Format::Cygwin);
+        assert_eq!(
+            cygwin,
+            SocketFile::Cygwin {
+                port: 50123,
+                secret: [
+                    0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05, 0x0c, 0x0b, 0x0a, 0x09, 0x10,
+                    0x0f, 0x0e, 0x0d,
+                ],
+            }
+        );
+
+        let msys = SocketFile::parse(MSYS).unwrap();
+        assert_eq!(msys.format(), Format::Msys);
+        assert_eq!(msys, SocketFile::Msys { port: 50124 });
+    }
+
+    #[test]
+    fn rejects_broken_files() {
+        assert_eq!(
+            SocketFile::parse(b"54321\nshort"),
+            Err(SocketFileError::InvalidNonce(5))
+        );
+        assert_eq!(
+            SocketFile::parse(b"port\n0123456789abcdef"),
+            Err(SocketFileError::InvalidPort("port".into()))
+        );
+        assert_eq!(
+            SocketFile::parse(b"!<socket >50123 d 01020304-05060708-090a0b0c-0d0e0f10"),
+            Err(SocketFileError::UnsupportedType("d".into()))
+        );
+        assert_eq!(
+            SocketFile::parse(b"!<socket >50123 s 0102-05060708-090a0b0c-0d0e0f10"),
+            Err(SocketFileError::InvalidSecret(
+                "0102-05060708-090a0b0c-0d0e0f10".into()
+            ))
+        );
+        assert!(matches!(
+            SocketFile::parse(b"!<socket >70000"),
+            Err(SocketFileError::InvalidPort(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sends_the_libassuan_nonce() {
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let file = SocketFile::Libassuan {
+            port,
+            nonce: *b"0123456789abcdef",
+        };
+
+        let (client, server) = tokio::join!(file.connect(), listener.accept());
+        drop(client.unwrap());
+        let mut received = Vec::new();
+        server.unwrap().0.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"0123456789abcdef");
+    }
+
+    #[tokio::test]
+    async fn detects_connections_closed_before_the_greeting() {
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let file = SocketFile::Msys { port };
+
+        // Rejected, as with a nonce from an earlier agent.
+        let (client, server) = tokio::join!(file.connect(), listener.accept());
+        drop(server.unwrap());
+        let err = await_greeting(&client.unwrap()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Greeted, and the greeting is still there to be relayed.
+        let (client, server) = tokio::join!(file.connect(), listener.accept());
+        server.unwrap().0.write_all(b"OK\n").await.unwrap();
+        let mut client = client.unwrap();
+        await_greeting(&client).await.unwrap();
+        let mut greeting = [0; 3];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"OK\n");
+    }
+
+    #[tokio::test]
+    async fn performs_the_cygwin_handshake() {
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let secret = *b"fedcba9876543210";
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = [0; NONCE_LEN];
+            stream.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, secret);
+            stream.write_all(&received).await.unwrap();
+
+            let mut cred = [0; CYGWIN_CRED_LEN];
+            stream.read_exact(&mut cred).await.unwrap();
+            assert_eq!(cred[..4], process::id().to_le_bytes());
+            stream.write_all(&[0; CYGWIN_CRED_LEN]).await.unwrap();
+            stream.write_all(b"OK hello\n").await.unwrap();
+        });
+
+        let mut stream = SocketFile::Cygwin { port, secret }.connect().await.unwrap();
+        let mut greeting = String::new();
+        stream.read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "OK hello\n");
+        server.await.unwrap();
+
+        // A peer that does not know the secret is refused.
+        let listener = TcpListener::bind("localhost:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&[0; NONCE_LEN]).await.unwrap();
+        });
+        let err = SocketFile::Cygwin { port, secret }
+            .connect()
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
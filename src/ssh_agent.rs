@@ -0,0 +1,839 @@
+//! The SSH agent protocol (draft-miller-ssh-agent).
+//!
+//! Every message is a `uint32` length followed by that many bytes, the first
+//! of which is the message type. Requests and responses strictly alternate on
+//! a connection, so a message-aware relay reads one request from the client,
+//! hands it to an [`Agent`] and writes the single response back.
+
+use serde::Deserialize;
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest message accepted, matching OpenSSH's `AGENT_MAX_LEN`.
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+pub const SSH_AGENT_FAILURE: u8 = 5;
+pub const SSH_AGENT_SUCCESS: u8 = 6;
+pub const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+pub const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+pub const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+pub const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+pub const SSH_AGENTC_ADD_IDENTITY: u8 = 17;
+pub const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+pub const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+pub const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
+pub const SSH_AGENTC_LOCK: u8 = 22;
+pub const SSH_AGENTC_UNLOCK: u8 = 23;
+pub const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
+pub const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
+pub const SSH_AGENTC_EXTENSION: u8 = 27;
+pub const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
+
+/// Extension OpenSSH sends to bind a connection to the host it logs in to.
+pub const SESSION_BIND: &str = "session-bind@openssh.com";
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("Empty SSH agent message")]
+    Empty,
+
+    #[error("SSH agent message of {0} bytes exceeds the {MAX_MESSAGE_LEN} byte limit")]
+    TooLarge(usize),
+
+    #[error("Truncated SSH agent message")]
+    Truncated,
+
+    #[error("{0} trailing bytes after SSH agent message")]
+    TrailingBytes(usize),
+
+    #[error("SSH agent message field is not valid UTF-8")]
+    InvalidUtf8,
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A message sent by a client to the agent. Variants are named after the
+/// message types in the draft.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    RequestIdentities,
+    SignRequest {
+        key_blob: Vec<u8>,
+        data: Vec<u8>,
+        flags: u32,
+    },
+    /// `ADD_IDENTITY` or `ADD_ID_CONSTRAINED`. The key material and any
+    /// constraints are kept as-is after the key type.
+    AddIdentity {
+        constrained: bool,
+        key_type: String,
+        rest: Vec<u8>,
+    },
+    RemoveIdentity {
+        key_blob: Vec<u8>,
+    },
+    RemoveAllIdentities,
+    /// `ADD_SMARTCARD_KEY` or `ADD_SMARTCARD_KEY_CONSTRAINED`.
+    AddSmartcardKey {
+        constrained: bool,
+        body: Vec<u8>,
+    },
+    RemoveSmartcardKey {
+        body: Vec<u8>,
+    },
+    Lock {
+        passphrase: Vec<u8>,
+    },
+    Unlock {
+        passphrase: Vec<u8>,
+    },
+    Extension {
+        name: String,
+        contents: Vec<u8>,
+    },
+    /// A message type this parser does not know about, passed through as-is.
+    Unknown {
+        kind: u8,
+        body: Vec<u8>,
+    },
+}
+
+/// The kinds of request a policy can allow, as named on the command line and
+/// in the config file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// `REQUEST_IDENTITIES`
+    List,
+    /// `SIGN_REQUEST`
+    Sign,
+    /// `ADD_IDENTITY` and `ADD_ID_CONSTRAINED`
+    Add,
+    /// `REMOVE_IDENTITY`
+    Remove,
+    /// `REMOVE_ALL_IDENTITIES`
+    RemoveAll,
+    /// `ADD_SMARTCARD_KEY` and `ADD_SMARTCARD_KEY_CONSTRAINED`
+    AddSmartcard,
+    /// `REMOVE_SMARTCARD_KEY`
+    RemoveSmartcard,
+    /// `LOCK`
+    Lock,
+    /// `UNLOCK`
+    Unlock,
+    /// `EXTENSION`, whatever the extension
+    Extension,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = clap::ValueEnum::to_possible_value(self).expect("no skipped variants");
+        f.write_str(value.get_name())
+    }
+}
+
+/// A message sent by the agent in reply to a [`Request`].
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Response {
+    Failure,
+    /// `SUCCESS`, with any extension-specific contents that follow it.
+    Success {
+        contents: Vec<u8>,
+    },
+    IdentitiesAnswer(Vec<Identity>),
+    SignResponse {
+        signature: Vec<u8>,
+    },
+    ExtensionFailure,
+    Unknown {
+        kind: u8,
+        body: Vec<u8>,
+    },
+}
+
+/// A public key held by the agent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub key_blob: Vec<u8>,
+    /// Usually UTF-8, but the protocol does not require it.
+    pub comment: Vec<u8>,
+}
+
+/// The contents of a [`SESSION_BIND`] extension request: the server's host
+/// key and its signature over the session, so that later sign requests on
+/// the connection can be tied to that host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionBind {
+    pub host_key: Vec<u8>,
+    pub session_id: Vec<u8>,
+    pub signature: Vec<u8>,
+    /// Whether the agent connection was forwarded to that host.
+    pub forwarding: bool,
+}
+
+impl SessionBind {
+    /// Parses the contents of a [`SESSION_BIND`] extension request.
+    pub fn decode(contents: &[u8]) -> Result<SessionBind, ProtocolError> {
+        let mut r = Reader(contents);
+        let bind = SessionBind {
+            host_key: r.string()?.to_vec(),
+            session_id: r.string()?.to_vec(),
+            signature: r.string()?.to_vec(),
+            forwarding: r.take(1)?[0] != 0,
+        };
+        r.finish()?;
+        Ok(bind)
+    }
+
+    /// Encodes the contents of a [`SESSION_BIND`] extension request.
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        put_string(&mut w, &self.host_key);
+        put_string(&mut w, &self.session_id);
+        put_string(&mut w, &self.signature);
+        w.push(self.forwarding.into());
+        w
+    }
+}
+
+impl Request {
+    /// The operation this request performs, or `None` for unknown types.
+    pub fn operation(&self) -> Option<Operation> {
+        Some(match self {
+            Request::RequestIdentities => Operation::List,
+            Request::SignRequest { .. } => Operation::Sign,
+            Request::AddIdentity { .. } => Operation::Add,
+            Request::RemoveIdentity { .. } => Operation::Remove,
+            Request::RemoveAllIdentities => Operation::RemoveAll,
+            Request::AddSmartcardKey { .. } => Operation::AddSmartcard,
+            Request::RemoveSmartcardKey { .. } => Operation::RemoveSmartcard,
+            Request::Lock { .. } => Operation::Lock,
+            Request::Unlock { .. } => Operation::Unlock,
+            Request::Extension { .. } => Operation::Extension,
+            Request::Unknown { .. } => return None,
+        })
+    }
+
+    /// Parses a message body (type byte included, length prefix excluded).
+    pub fn decode(message: &[u8]) -> Result<Request, ProtocolError> {
+        let (&kind, body) = message.split_first().ok_or(ProtocolError::Empty)?;
+        let mut r = Reader(body);
+
+        let request = match kind {
+            SSH_AGENTC_REQUEST_IDENTITIES => Request::RequestIdentities,
+            SSH_AGENTC_SIGN_REQUEST => Request::SignRequest {
+                key_blob: r.string()?.to_vec(),
+                data: r.string()?.to_vec(),
+                flags: r.u32()?,
+            },
+            SSH_AGENTC_ADD_IDENTITY | SSH_AGENTC_ADD_ID_CONSTRAINED => Request::AddIdentity {
+                constrained: kind == SSH_AGENTC_ADD_ID_CONSTRAINED,
+                key_type: r.utf8()?,
+                rest: r.rest(),
+            },
+            SSH_AGENTC_REMOVE_IDENTITY => Request::RemoveIdentity {
+                key_blob: r.string()?.to_vec(),
+            },
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => Request::RemoveAllIdentities,
+            SSH_AGENTC_ADD_SMARTCARD_KEY | SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED => {
+                Request::AddSmartcardKey {
+                    constrained: kind == SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED,
+                    body: r.rest(),
+                }
+            }
+            SSH_AGENTC_REMOVE_SMARTCARD_KEY => Request::RemoveSmartcardKey { body: r.rest() },
+            SSH_AGENTC_LOCK => Request::Lock {
+                passphrase: r.string()?.to_vec(),
+            },
+            SSH_AGENTC_UNLOCK => Request::Unlock {
+                passphrase: r.string()?.to_vec(),
+            },
+            SSH_AGENTC_EXTENSION => Request::Extension {
+                name: r.utf8()?,
+                contents: r.rest(),
+            },
+            kind => Request::Unknown {
+                kind,
+                body: r.rest(),
+            },
+        };
+
+        r.finish()?;
+        Ok(request)
+    }
+
+    /// Encodes the message body (type byte included, length prefix excluded).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        match self {
+            Request::RequestIdentities => w.push(SSH_AGENTC_REQUEST_IDENTITIES),
+            Request::SignRequest {
+                key_blob,
+                data,
+                flags,
+            } => {
+                w.push(SSH_AGENTC_SIGN_REQUEST);
+                put_string(&mut w, key_blob);
+                put_string(&mut w, data);
+                w.extend_from_slice(&flags.to_be_bytes());
+            }
+            Request::AddIdentity {
+                constrained,
+                key_type,
+                rest,
+            } => {
+                w.push(if *constrained {
+                    SSH_AGENTC_ADD_ID_CONSTRAINED
+                } else {
+                    SSH_AGENTC_ADD_IDENTITY
+                });
+                put_string(&mut w, key_type.as_bytes());
+                w.extend_from_slice(rest);
+            }
+            Request::RemoveIdentity { key_blob } => {
+                w.push(SSH_AGENTC_REMOVE_IDENTITY);
+                put_string(&mut w, key_blob);
+            }
+            Request::RemoveAllIdentities => w.push(SSH_AGENTC_REMOVE_ALL_IDENTITIES),
+            Request::AddSmartcardKey { constrained, body } => {
+                w.push(if *constrained {
+                    SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED
+                } else {
+                    SSH_AGENTC_ADD_SMARTCARD_KEY
+                });
+                w.extend_from_slice(body);
+            }
+            Request::RemoveSmartcardKey { body } => {
+                w.push(SSH_AGENTC_REMOVE_SMARTCARD_KEY);
+                w.extend_from_slice(body);
+            }
+            Request::Lock { passphrase } => {
+                w.push(SSH_AGENTC_LOCK);
+                put_string(&mut w, passphrase);
+            }
+            Request::Unlock { passphrase } => {
+                w.push(SSH_AGENTC_UNLOCK);
+                put_string(&mut w, passphrase);
+            }
+            Request::Extension { name, contents } => {
+                w.push(SSH_AGENTC_EXTENSION);
+                put_string(&mut w, name.as_bytes());
+                w.extend_from_slice(contents);
+            }
+            Request::Unknown { kind, body } => {
+                w.push(*kind);
+                w.extend_from_slice(body);
+            }
+        }
+        w
+    }
+}
+
+impl Response {
+    /// Parses a message body (type byte included, length prefix excluded).
+    pub fn decode(message: &[u8]) -> Result<Response, ProtocolError> {
+        let (&kind, body) = message.split_first().ok_or(ProtocolError::Empty)?;
+        let mut r = Reader(body);
+
+        let response = match kind {
+            SSH_AGENT_FAILURE => Response::Failure,
+            SSH_AGENT_SUCCESS => Response::Success { contents: r.rest() },
+            SSH_AGENT_IDENTITIES_ANSWER => {
+                let count = r.u32()?;
+                // Each identity takes at least 8 bytes, which bounds the
+                // allocation by the message size rather than by `count`.
+                let mut identities = Vec::with_capacity((count as usize).min(body.len() / 8));
+                for _ in 0..count {
+                    identities.push(Identity {
+                        key_blob: r.string()?.to_vec(),
+                        comment: r.string()?.to_vec(),
+                    });
+                }
+                Response::IdentitiesAnswer(identities)
+            }
+            SSH_AGENT_SIGN_RESPONSE => Response::SignResponse {
+                signature: r.string()?.to_vec(),
+            },
+            SSH_AGENT_EXTENSION_FAILURE => Response::ExtensionFailure,
+            kind => Response::Unknown {
+                kind,
+                body: r.rest(),
+            },
+        };
+
+        r.finish()?;
+        Ok(response)
+    }
+
+    /// Encodes the message body (type byte included, length prefix excluded).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        match self {
+            Response::Failure => w.push(SSH_AGENT_FAILURE),
+            Response::Success { contents } => {
+                w.push(SSH_AGENT_SUCCESS);
+                w.extend_from_slice(contents);
+            }
+            Response::IdentitiesAnswer(identities) => {
+                w.push(SSH_AGENT_IDENTITIES_ANSWER);
+                w.extend_from_slice(&(identities.len() as u32).to_be_bytes());
+                for identity in identities {
+                    put_string(&mut w, &identity.key_blob);
+                    put_string(&mut w, &identity.comment);
+                }
+            }
+            Response::SignResponse { signature } => {
+                w.push(SSH_AGENT_SIGN_RESPONSE);
+                put_string(&mut w, signature);
+            }
+            Response::ExtensionFailure => w.push(SSH_AGENT_EXTENSION_FAILURE),
+            Response::Unknown { kind, body } => {
+                w.push(*kind);
+                w.extend_from_slice(body);
+            }
+        }
+        w
+    }
+}
+
+/// The key type (e.g. `ssh-ed25519`) encoded at the start of a public key blob.
+pub fn key_type(key_blob: &[u8]) -> Option<&str> {
+    let bytes = Reader(key_blob).string().ok()?;
+    std::str::from_utf8(bytes).ok()
+}
+
+/// The `SHA256:...` fingerprint of a public key blob, as printed by `ssh-add -l`.
+pub fn fingerprint(key_blob: &[u8]) -> String {
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+
+    let digest = Sha256::digest(key_blob);
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+    )
+}
+
+/// Cursor over the SSH wire encoding (RFC 4251 section 5).
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.0.len() < n {
+            return Err(ProtocolError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn utf8(&mut self) -> Result<String, ProtocolError> {
+        let bytes = self.string()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8)
+    }
+
+    fn rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.0).to_vec()
+    }
+
+    fn finish(self) -> Result<(), ProtocolError> {
+        match self.0.len() {
+            0 => Ok(()),
+            n => Err(ProtocolError::TrailingBytes(n)),
+        }
+    }
+}
+
+fn put_string(w: &mut Vec<u8>, bytes: &[u8]) {
+    w.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    w.extend_from_slice(bytes);
+}
+
+/// Reads one length-prefixed message, or `None` on a clean EOF.
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    if reader.read(&mut len[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..]).await?;
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Err(ProtocolError::Empty.into());
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(ProtocolError::TooLarge(len).into());
+    }
+
+    let mut message = vec![0; len];
+    reader.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+/// Writes one message with its length prefix and flushes it.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> io::Result<()> {
+    let mut framed = Vec::with_capacity(4 + message.len());
+    put_string(&mut framed, message);
+    writer.write_all(&framed).await?;
+    writer.flush().await
+}
+
+/// Something that answers SSH agent requests.
+pub trait Agent {
+    fn call(&mut self, request: Request) -> impl Future<Output = io::Result<Response>> + Send;
+}
+
+/// A connection to a real agent.
+pub struct Upstream<S> {
+    stream: S,
+}
+
+impl<S> Upstream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+}
+
+impl<S> Agent for Upstream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn call(&mut self, request: Request) -> io::Result<Response> {
+        write_message(&mut self.stream, &request.encode()).await?;
+        let message = read_message(&mut self.stream)
+            .await?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        // An answer we cannot parse is reported to the client as a failure
+        // rather than passed on unchecked.
+        Ok(Response::decode(&message).unwrap_or(Response::Failure))
+    }
+}
+
+/// Answers requests from `client` with `agent` until the client disconnects.
+///
+/// Requests that cannot be parsed are answered with `SSH_AGENT_FAILURE`
+/// without reaching the agent.
+pub async fn serve<C, A>(client: C, agent: &mut A) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite,
+    A: Agent,
+{
+    let (mut reader, mut writer) = tokio::io::split(client);
+
+    while let Some(message) = read_message(&mut reader).await? {
+        let response = match Request::decode(&message) {
+            Ok(request) => {
+                tracing::debug!(operation = ?request.operation(), "SSH agent request");
+                agent.call(request).await?
+            }
+            Err(err) => {
+                tracing::debug!(%err, "malformed SSH agent request");
+                Response::Failure
+            }
+        };
+        write_message(&mut writer, &response.encode()).await?;
+    }
+
+    Ok(())
+}
+
+/// Keys, requests and an agent for the tests of the modules built on
+/// [`Agent`].
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// A public key blob of `key_type` whose key is 32 `key` bytes.
+    pub fn key_blob(key_type: &str, key: u8) -> Vec<u8> {
+        let mut key_blob = Vec::new();
+        put_string(&mut key_blob, key_type.as_bytes());
+        put_string(&mut key_blob, &[key; 32]);
+        key_blob
+    }
+
+    /// An `ssh-ed25519` key blob.
+    pub fn key(key: u8) -> Vec<u8> {
+        key_blob("ssh-ed25519", key)
+    }
+
+    pub fn identity(key: u8, comment: &str) -> Identity {
+        Identity {
+            key_blob: self::key(key),
+            comment: comment.as_bytes().to_vec(),
+        }
+    }
+
+    /// Asks for a signature of 42 bytes with flag 2 (`rsa-sha2-256`).
+    pub fn sign(key_blob: &[u8]) -> Request {
+        Request::SignRequest {
+            key_blob: key_blob.to_vec(),
+            data: vec![0; 42],
+            flags: 2,
+        }
+    }
+
+    /// Holds `identities` and signs with them, answering with its `name` as
+    /// the signature, and accepts everything else. Records every request
+    /// it answers, and errors on every call once `broken` is set.
+    pub struct FakeAgent {
+        pub name: u8,
+        pub identities: Vec<Identity>,
+        pub requests: Vec<Request>,
+        pub broken: bool,
+    }
+
+    impl FakeAgent {
+        pub fn new(name: u8, identities: Vec<Identity>) -> Self {
+            Self {
+                name,
+                identities,
+                requests: Vec::new(),
+                broken: false,
+            }
+        }
+    }
+
+    impl Agent for FakeAgent {
+        async fn call(&mut self, request: Request) -> io::Result<Response> {
+            if self.broken {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.requests.push(request.clone());
+            Ok(match request {
+                Request::RequestIdentities => Response::IdentitiesAnswer(self.identities.clone()),
+                Request::SignRequest { key_blob, .. }
+                    if self.identities.iter().any(|id| id.key_blob == key_blob) =>
+                {
+                    Response::SignResponse {
+                        signature: vec![self.name],
+                    }
+                }
+                Request::SignRequest { .. } => Response::Failure,
+                _ => Response::Success { contents: vec![] },
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{FakeAgent, identity, key};
+    use super::*;
+    use tokio::io::duplex;
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::RequestIdentities,
+            Request::SignRequest {
+                key_blob: key(1),
+                data: b"session data".to_vec(),
+                flags: 2,
+            },
+            Request::AddIdentity {
+                constrained: true,
+                key_type: "ssh-ed25519".into(),
+                rest: vec![1, 2, 3],
+            },
+            Request::RemoveIdentity { key_blob: key(2) },
+            Request::RemoveAllIdentities,
+            Request::AddSmartcardKey {
+                constrained: false,
+                body: vec![0, 0, 0, 1, b'x', 0, 0, 0, 0],
+            },
+            Request::RemoveSmartcardKey { body: vec![9] },
+            Request::Lock {
+                passphrase: b"hunter2".to_vec(),
+            },
+            Request::Unlock {
+                passphrase: b"hunter2".to_vec(),
+            },
+            Request::Extension {
+                name: "session-bind@openssh.com".into(),
+                contents: vec![0, 0, 0, 0],
+            },
+            Request::Unknown {
+                kind: 200,
+                body: vec![1, 2],
+            },
+        ];
+
+        for request in requests {
+            let encoded = request.encode();
+            assert_eq!(Request::decode(&encoded).unwrap(), request);
+        }
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = [
+            Response::Failure,
+            Response::Success { contents: vec![] },
+            Response::Success {
+                contents: vec![0, 0, 0, 1, 7],
+            },
+            Response::IdentitiesAnswer(vec![identity(1, "a"), identity(2, "bb")]),
+            Response::IdentitiesAnswer(vec![]),
+            Response::SignResponse {
+                signature: vec![1; 64],
+            },
+            Response::ExtensionFailure,
+            Response::Unknown {
+                kind: 99,
+                body: vec![],
+            },
+        ];
+
+        for response in responses {
+            let encoded = response.encode();
+            assert_eq!(Response::decode(&encoded).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert_eq!(Request::decode(&[]), Err(ProtocolError::Empty));
+        assert_eq!(
+            Request::decode(&[SSH_AGENTC_SIGN_REQUEST, 0, 0, 0, 9, 1]),
+            Err(ProtocolError::Truncated)
+        );
+        assert_eq!(
+            Request::decode(&[SSH_AGENTC_REQUEST_IDENTITIES, 0]),
+            Err(ProtocolError::TrailingBytes(1))
+        );
+        assert_eq!(
+            Response::decode(&[SSH_AGENT_IDENTITIES_ANSWER, 255, 255, 255, 255]),
+            Err(ProtocolError::Truncated)
+        );
+        assert_eq!(
+            Request::decode(&[SSH_AGENTC_EXTENSION, 0, 0, 0, 1, 0xff]),
+            Err(ProtocolError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn parses_session_binds() {
+        let bind = SessionBind {
+            host_key: key(4),
+            session_id: vec![7; 32],
+            signature: vec![8; 64],
+            forwarding: true,
+        };
+        assert_eq!(SessionBind::decode(&bind.encode()), Ok(bind.clone()));
+
+        let encoded = bind.encode();
+        assert_eq!(
+            SessionBind::decode(&encoded[..encoded.len() - 1]),
+            Err(ProtocolError::Truncated)
+        );
+        assert_eq!(
+            SessionBind::decode(&[&encoded[..], &[0]].concat()),
+            Err(ProtocolError::TrailingBytes(1))
+        );
+    }
+
+    #[test]
+    fn describes_key_blobs() {
+        let key_blob = key(1);
+        assert_eq!(key_type(&key_blob), Some("ssh-ed25519"));
+        assert_eq!(key_type(&[0, 0, 0, 9, b'x']), None);
+
+        // `ssh-keygen -lf` on the same public key prints this fingerprint.
+        assert_eq!(
+            fingerprint(&key_blob),
+            "SHA256:RXm/ruZ0eTzRXKwi1AQEDynB0VgHQ2ac9KPSFdf/YnA"
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_messages() {
+        let (mut a, mut b) = duplex(1024);
+        write_message(&mut a, &[SSH_AGENTC_REQUEST_IDENTITIES])
+            .await
+            .unwrap();
+        drop(a);
+
+        assert_eq!(
+            read_message(&mut b).await.unwrap(),
+            Some(vec![SSH_AGENTC_REQUEST_IDENTITIES])
+        );
+        assert_eq!(read_message(&mut b).await.unwrap(), None);
+
+        let mut oversized = &[0x7f, 0xff, 0xff, 0xff][..];
+        let err = read_message(&mut oversized).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    async fn call(client: &mut tokio::io::DuplexStream, request: Request) -> Response {
+        write_message(client, &request.encode()).await.unwrap();
+        let message = read_message(client).await.unwrap().unwrap();
+        Response::decode(&message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn relays_requests_through_the_parser() {
+        let (upstream, agent_end) = duplex(4096);
+        let (mut client, relay_end) = duplex(4096);
+        tokio::spawn(async move {
+            serve(agent_end, &mut FakeAgent::new(7, vec![identity(1, "a")])).await
+        });
+        let relay =
+            tokio::spawn(async move { serve(relay_end, &mut Upstream::new(upstream)).await });
+
+        assert_eq!(
+            call(&mut client, Request::RequestIdentities).await,
+            Response::IdentitiesAnswer(vec![identity(1, "a")])
+        );
+        assert_eq!(
+            call(
+                &mut client,
+                Request::SignRequest {
+                    key_blob: key(1),
+                    data: b"x".to_vec(),
+                    flags: 0,
+                }
+            )
+            .await,
+            Response::SignResponse { signature: vec![7] }
+        );
+
+        // Garbage is answered locally and the connection stays usable.
+        write_message(&mut client, &[SSH_AGENTC_SIGN_REQUEST, 0])
+            .await
+            .unwrap();
+        let message = read_message(&mut client).await.unwrap().unwrap();
+        assert_eq!(Response::decode(&message).unwrap(), Response::Failure);
+        assert_eq!(
+            call(&mut client, Request::RemoveAllIdentities).await,
+            Response::Success { contents: vec![] }
+        );
+
+        drop(client);
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+//! Connecting to TCP services on the Windows side.
+//!
+//! With WSL2's NAT networking, services that only listen on Windows'
+//! localhost cannot be reached from WSL directly, but a relay started
+//! through interop runs on Windows and can connect to them.
+
+use crate::retry::RetryPolicy;
+use std::{io, time::Duration};
+use tokio::net::TcpStream;
+
+/// Checks that `address` looks like `host:port`, as `TcpStream::connect`
+/// would otherwise only say so once the relay runs.
+pub fn check_address(address: &str) -> Result<String, String> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| format!("invalid address {address:?}, expected HOST:PORT"))?;
+    if host.is_empty() {
+        return Err(format!("address {address:?} has no host"));
+    }
+    port.parse::<u16>()
+        .map_err(|_| format!("invalid port {port:?} in address {address:?}"))?;
+    Ok(address.to_owned())
+}
+
+/// Connects to `address`, retrying refused connections and attempts that
+/// took longer than `timeout`.
+pub async fn connect(
+    address: &str,
+    timeout: Option<Duration>,
+    retry: &RetryPolicy,
+) -> io::Result<TcpStream> {
+    let attempt = || async {
+        let connect = TcpStream::connect(address);
+        let Some(timeout) = timeout else {
+            return connect.await;
+        };
+        tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no connection after {timeout:?}"),
+                ))
+            })
+    };
+    let stream = retry
+        .retry(attempt, |err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+            )
+        })
+        .await?;
+
+    tracing::info!(address, "connected to TCP service");
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn checks_addresses() {
+        assert_eq!(
+            check_address("localhost:5432").as_deref(),
+            Ok("localhost:5432")
+        );
+        assert!(check_address("[::1]:27000").is_ok());
+        assert!(check_address("localhost").is_err());
+        assert!(check_address(":80").is_err());
+        assert!(check_address("localhost:http").is_err());
+        assert!(check_address("localhost:65536").is_err());
+    }
+
+    #[tokio::test]
+    async fn retries_refused_connections() {
+        // Find a free port, then stop listening on it.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let retry = RetryPolicy {
+            max_attempts: Some(3),
+            ..RetryPolicy::constant(Duration::from_millis(10), Duration::from_secs(5))
+        };
+        let err = connect(&address, None, &retry).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let listener = TcpListener::bind(&address).await.unwrap();
+        let (client, accepted) = tokio::join!(
+            connect(&address, Some(Duration::from_secs(5)), &retry),
+            listener.accept()
+        );
+        assert_eq!(client.unwrap().local_addr().unwrap(), accepted.unwrap().1);
+    }
+}
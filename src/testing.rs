@@ -0,0 +1,12 @@
+//! Helpers shared by the tests of several modules.
+
+use std::{fs, path::PathBuf};
+
+/// An empty directory for the test `name`, unique to this test run. Whatever
+/// an earlier run left in it is removed first.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wsl2-bridge-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
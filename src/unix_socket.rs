@@ -0,0 +1,183 @@
+//! Connecting to Unix sockets, including the AF_UNIX sockets that Windows
+//! programs (e.g. newer Gpg4win builds or Docker Desktop helpers) create
+//! on NTFS.
+//!
+//! Tokio has no Unix socket type on Windows, but Winsock reads and writes
+//! AF_UNIX sockets with the same calls as TCP ones, so a connected socket is
+//! driven as a [`tokio::net::TcpStream`] there.
+
+use crate::{logging, retry::RetryPolicy};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Longest path a `sockaddr_un` holds, leaving room for the terminating NUL.
+/// Linux and Windows both have 108 bytes.
+const MAX_PATH_LEN: usize = 107;
+
+/// A connected Unix socket.
+#[cfg(unix)]
+pub type UnixSocket = tokio::net::UnixStream;
+#[cfg(windows)]
+pub type UnixSocket = tokio::net::TcpStream;
+
+/// Checks that `path` fits in a socket address, as connecting would
+/// otherwise only say so once the relay runs. A WSL path is measured as
+/// [`connect`] translates it.
+pub fn check_path(path: &str) -> Result<PathBuf, String> {
+    check_host_path(path, &logging::host_path(Path::new(path)))
+}
+
+/// Checks `path`, which this process connects to as `host`.
+fn check_host_path(path: &str, host: &Path) -> Result<PathBuf, String> {
+    if path.is_empty() {
+        return Err("socket path must not be empty".into());
+    }
+    if path.contains('\0') {
+        return Err(format!("socket path {path:?} contains a NUL byte"));
+    }
+    let len = host.as_os_str().len();
+    if len > MAX_PATH_LEN {
+        let translated = if host == Path::new(path) {
+            String::new()
+        } else {
+            format!(" as {host:?}")
+        };
+        return Err(format!(
+            "socket path {path:?} is {len} bytes long{translated}, at most {MAX_PATH_LEN} fit"
+        ));
+    }
+    Ok(path.into())
+}
+
+/// Connects to the socket at `path`, retrying while it does not exist yet
+/// or nothing listens on it. WSL paths work as for `--log-file`.
+pub async fn connect(path: &Path, retry: &RetryPolicy) -> io::Result<UnixSocket> {
+    let path = logging::host_path(path);
+    let stream = retry
+        .retry(
+            || connect_once(&path),
+            |err| {
+                matches!(
+                    err.kind(),
+                    io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+                )
+            },
+        )
+        .await?;
+
+    tracing::info!(path = %path.display(), "connected to Unix socket");
+    Ok(stream)
+}
+
+#[cfg(unix)]
+async fn connect_once(path: &Path) -> io::Result<UnixSocket> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(windows)]
+async fn connect_once(path: &Path) -> io::Result<UnixSocket> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    let address = SockAddr::unix(path)?;
+    // Connecting blocks while the listener's backlog is full.
+    let socket = tokio::task::spawn_blocking(move || {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.connect(&address)?;
+        socket.set_nonblocking(true)?;
+        io::Result::Ok(socket)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    tokio::net::TcpStream::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_paths() {
+        assert_eq!(
+            check_path(r"C:\Users\me\AppData\Local\docker.sock"),
+            Ok(r"C:\Users\me\AppData\Local\docker.sock".into())
+        );
+        assert_eq!(
+            check_path("/mnt/c/Users/me/agent.sock"),
+            Ok("/mnt/c/Users/me/agent.sock".into())
+        );
+        assert_eq!(check_path(""), Err("socket path must not be empty".into()));
+        assert_eq!(
+            check_path("a\0b"),
+            Err("socket path \"a\\0b\" contains a NUL byte".into())
+        );
+
+        let longest = "x".repeat(MAX_PATH_LEN);
+        assert!(check_path(&longest).is_ok());
+        assert_eq!(
+            check_path(&format!("{longest}x")),
+            Err(format!(
+                "socket path \"{longest}x\" is 108 bytes long, at most 107 fit"
+            ))
+        );
+    }
+
+    #[test]
+    fn checks_translated_paths() {
+        // `\\wsl.localhost\Ubuntu` makes the path 22 bytes longer.
+        let path = format!("/{}", "x".repeat(MAX_PATH_LEN - 1));
+        let host = PathBuf::from(format!(
+            r"\\wsl.localhost\Ubuntu\{}",
+            "x".repeat(MAX_PATH_LEN - 1)
+        ));
+        assert_eq!(
+            check_host_path(&path, &host),
+            Err(format!(
+                "socket path {path:?} is 129 bytes long as {host:?}, at most 107 fit"
+            ))
+        );
+
+        let path = "/mnt/c/Users/me/agent.sock";
+        assert_eq!(
+            check_host_path(path, Path::new(r"C:\Users\me\agent.sock")),
+            Ok(path.into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn waits_for_the_socket_to_appear() {
+        use std::time::Duration;
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::UnixListener,
+        };
+
+        let path = crate::testing::temp_dir("unix-socket").join("agent.sock");
+
+        let retry = RetryPolicy {
+            max_attempts: Some(2),
+            ..RetryPolicy::constant(Duration::from_millis(10), Duration::from_secs(5))
+        };
+        let err = connect(&path, &retry).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let retry = RetryPolicy {
+            max_attempts: Some(50),
+            ..retry
+        };
+        let listener = async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let listener = UnixListener::bind(&path).unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        };
+        let (client, ()) = tokio::join!(connect(&path, &retry), listener);
+
+        let mut greeting = String::new();
+        client.unwrap().read_to_string(&mut greeting).await.unwrap();
+        assert_eq!(greeting, "hello");
+    }
+}
@@ -0,0 +1,299 @@
+//! Hyper-V sockets between WSL and Windows, a transport for multiplexed
+//! streams that needs neither WSL interop nor a process start.
+//!
+//! The Windows `serve` process listens with `AF_HYPERV` on the service GUID
+//! of a vsock port, and `listen` or `daemon` in WSL connects to that port
+//! on the host with `AF_VSOCK`. Hyper-V only lets a VM reach services whose
+//! GUID is registered under `GuestCommunicationServices` in the registry.
+
+use std::io;
+
+/// Service GUIDs of vsock ports are this template with the port as the
+/// first field (`HV_GUID_VSOCK_TEMPLATE`).
+const VSOCK_TEMPLATE: u128 = 0x00000000_facb_11e6_bd58_64006a7986d3;
+
+/// The Hyper-V socket service GUID that vsock `port` maps to.
+pub fn service_id(port: u32) -> u128 {
+    VSOCK_TEMPLATE | u128::from(port) << 96
+}
+
+/// Formats `guid` as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+pub fn format_guid(guid: u128) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        guid >> 96,
+        (guid >> 80) & 0xffff,
+        (guid >> 64) & 0xffff,
+        (guid >> 48) & 0xffff,
+        guid & 0xffff_ffff_ffff
+    )
+}
+
+/// Parses a GUID like `{a42e7cda-d03f-480c-9ce2-a4de20abb878}`, with or
+/// without braces.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn parse_guid(value: &str) -> Result<u128, String> {
+    let invalid =
+        || format!("invalid GUID {value:?}, expected xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx");
+    let digits = value
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(value);
+
+    let groups: Vec<&str> = digits.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] || !digits.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    u128::from_str_radix(&groups.concat(), 16).map_err(|_| invalid())
+}
+
+/// Parses the VM ID a Hyper-V socket listener accepts connections from.
+///
+/// The wildcard, broadcast and children IDs are refused: they would let
+/// every VM on the host open streams.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn parse_vm_id(value: &str) -> Result<u128, String> {
+    const WILDCARD: u128 = 0;
+    const BROADCAST: u128 = u128::MAX;
+    const CHILDREN: u128 = 0x90db8b89_0d35_4f79_8ce9_49ea0ac8b7cd;
+
+    match parse_guid(value)? {
+        WILDCARD | BROADCAST | CHILDREN => Err(format!(
+            "{value} would accept connections from every VM, give the ID of WSL's VM"
+        )),
+        vm_id => Ok(vm_id),
+    }
+}
+
+/// The host as seen from a VM (`VMADDR_CID_HOST`).
+#[cfg(unix)]
+pub const HOST_CID: u32 = 2;
+
+/// Connects to `serve` processes listening on vsock `port` of `cid`.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug)]
+pub struct Vsock {
+    pub cid: u32,
+    pub port: u32,
+}
+
+#[cfg(unix)]
+impl crate::backend::Transport for Vsock {
+    fn connect(&self) -> crate::backend::Connecting<'_> {
+        Box::pin(async move {
+            tracing::info!(
+                cid = self.cid,
+                port = self.port,
+                service = format_guid(service_id(self.port)),
+                "connecting over vsock"
+            );
+            let stream = connect(self.cid, self.port).await?;
+            Ok(Box::new(stream) as Box<dyn crate::endpoint::Stream>)
+        })
+    }
+}
+
+/// Connects to vsock `port` of `cid`.
+///
+/// Tokio has no vsock type, but a connected vsock is read and written like
+/// a Unix socket, so it is driven as a [`tokio::net::UnixStream`].
+#[cfg(target_os = "linux")]
+async fn connect(cid: u32, port: u32) -> io::Result<tokio::net::UnixStream> {
+    use socket2::{Domain, SockAddr, Socket, Type};
+
+    let socket = tokio::task::spawn_blocking(move || {
+        let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)?;
+        socket.connect(&SockAddr::vsock(cid, port))?;
+        socket.set_nonblocking(true)?;
+        io::Result::Ok(socket)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    tokio::net::UnixStream::from_std(socket.into())
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+async fn connect(_cid: u32, _port: u32) -> io::Result<tokio::net::UnixStream> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "vsock is only supported on Linux",
+    ))
+}
+
+/// Accepts Hyper-V socket connections from VMs.
+#[cfg(windows)]
+pub struct HvsockListener {
+    accepted: tokio::sync::mpsc::Receiver<io::Result<socket2::Socket>>,
+}
+
+#[cfg(windows)]
+impl HvsockListener {
+    /// Listens on the service GUID of vsock `port` for connections from the
+    /// VM `vm_id`.
+    pub fn bind(vm_id: u128, port: u32) -> io::Result<Self> {
+        use socket2::{Domain, Protocol, SockAddr, SockAddrStorage, Socket, Type};
+        use windows_sys::{
+            Win32::{
+                Networking::WinSock::AF_HYPERV,
+                System::Hypervisor::{HV_PROTOCOL_RAW, SOCKADDR_HV},
+            },
+            core::GUID,
+        };
+
+        let mut storage = SockAddrStorage::zeroed();
+        // SAFETY: SOCKADDR_HV is a socket address of this platform, and the
+        // storage is large enough to hold it.
+        let address = unsafe {
+            *storage.view_as::<SOCKADDR_HV>() = SOCKADDR_HV {
+                Family: AF_HYPERV,
+                Reserved: 0,
+                VmId: GUID::from_u128(vm_id),
+                ServiceId: GUID::from_u128(service_id(port)),
+            };
+            SockAddr::new(storage, size_of::<SOCKADDR_HV>() as _)
+        };
+
+        let socket = Socket::new(
+            Domain::from(i32::from(AF_HYPERV)),
+            Type::STREAM,
+            Some(Protocol::from(HV_PROTOCOL_RAW as i32)),
+        )?;
+        socket.bind(&address)?;
+        socket.listen(128)?;
+
+        // Accepting blocks, and a blocking task would keep the runtime from
+        // shutting down, so it gets a thread of its own. The thread stops at
+        // the first connection after the listener is dropped.
+        let (sender, accepted) = tokio::sync::mpsc::channel(1);
+        std::thread::spawn(move || {
+            loop {
+                let connection = socket.accept().map(|(connection, _)| connection);
+                if sender.blocking_send(connection).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { accepted })
+    }
+
+    /// Waits for the next connection.
+    pub async fn accept(&mut self) -> io::Result<tokio::io::DuplexStream> {
+        let connection = self
+            .accepted
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::other("Hyper-V socket listener stopped"))??;
+        Ok(blocking_stream(connection))
+    }
+}
+
+/// Bytes buffered in each direction of a Hyper-V socket connection.
+#[cfg(windows)]
+const BUFFER: usize = 64 * 1024;
+
+/// Tokio has no Hyper-V socket type, so `socket` is read and written with
+/// blocking calls on threads of its own and relayed to the returned stream.
+#[cfg(windows)]
+fn blocking_stream(socket: socket2::Socket) -> tokio::io::DuplexStream {
+    use std::{
+        io::{Read, Write},
+        net::Shutdown,
+        sync::Arc,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (stream, relayed) = tokio::io::duplex(BUFFER);
+    let (mut from_stream, mut to_stream) = tokio::io::split(relayed);
+    let socket = Arc::new(socket);
+    let runtime = tokio::runtime::Handle::current();
+
+    std::thread::spawn({
+        let socket = socket.clone();
+        let runtime = runtime.clone();
+        move || {
+            let mut buf = vec![0; BUFFER];
+            while let Ok(n @ 1..) = (&*socket).read(&mut buf) {
+                if runtime.block_on(to_stream.write_all(&buf[..n])).is_err() {
+                    break;
+                }
+            }
+            let _ = runtime.block_on(to_stream.shutdown());
+        }
+    });
+    std::thread::spawn(move || {
+        let mut buf = vec![0; BUFFER];
+        while let Ok(n @ 1..) = runtime.block_on(from_stream.read(&mut buf)) {
+            if (&*socket).write_all(&buf[..n]).is_err() {
+                break;
+            }
+        }
+        let _ = socket.shutdown(Shutdown::Write);
+    });
+
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_ports_to_service_ids() {
+        assert_eq!(
+            format_guid(service_id(5000)),
+            "00001388-facb-11e6-bd58-64006a7986d3"
+        );
+        assert_eq!(
+            format_guid(service_id(u32::MAX)),
+            "ffffffff-facb-11e6-bd58-64006a7986d3"
+        );
+    }
+
+    #[test]
+    fn parses_guids() {
+        let parent = 0xa42e7cda_d03f_480c_9cc2_a4de20abb878;
+        assert_eq!(
+            parse_guid("a42e7cda-d03f-480c-9cc2-a4de20abb878"),
+            Ok(parent)
+        );
+        assert_eq!(
+            parse_guid("{A42E7CDA-D03F-480C-9CC2-A4DE20ABB878}"),
+            Ok(parent)
+        );
+        assert_eq!(format_guid(parent), "a42e7cda-d03f-480c-9cc2-a4de20abb878");
+
+        for invalid in [
+            "",
+            "a42e7cda-d03f-480c-9cc2",
+            "a42e7cdad03f480c9cc2a4de20abb878",
+            "{a42e7cda-d03f-480c-9cc2-a4de20abb878",
+            "a42e7cda-d03f-480c-9cc2-a4de20abb87g",
+            "+42e7cda-d03f-480c-9cc2-a4de20abb878",
+        ] {
+            assert!(parse_guid(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_vm_ids_matching_every_vm() {
+        let wsl = "{5e1c1c6a-3a5b-4c7d-9e8f-0a1b2c3d4e5f}";
+        assert_eq!(parse_vm_id(wsl), Ok(0x5e1c1c6a_3a5b_4c7d_9e8f_0a1b2c3d4e5f));
+
+        for every in [
+            "00000000-0000-0000-0000-000000000000",
+            "ffffffff-ffff-ffff-ffff-ffffffffffff",
+            "90db8b89-0d35-4f79-8ce9-49ea0ac8b7cd",
+        ] {
+            assert_eq!(
+                parse_vm_id(every),
+                Err(format!(
+                    "{every} would accept connections from every VM, give the ID of WSL's VM"
+                ))
+            );
+        }
+        assert!(parse_vm_id("wsl").is_err());
+    }
+}